@@ -0,0 +1,16 @@
+//! Records the commit the binary was built from as `GIT_SHA`, for the startup banner. A `GIT_SHA`
+//! set in the build environment wins, for builds without the repository, like Docker's.
+
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+    let sha = std::env::var("GIT_SHA").ok().filter(|sha| !sha.is_empty()).or_else(|| {
+        let output = Command::new("git").args(["rev-parse", "--short", "HEAD"]).output().ok()?;
+        let sha = String::from_utf8(output.stdout).ok()?;
+        Some(sha.trim().to_string()).filter(|sha| output.status.success() && !sha.is_empty())
+    });
+    println!("cargo:rustc-env=GIT_SHA={}", sha.as_deref().unwrap_or("unknown"));
+}
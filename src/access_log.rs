@@ -0,0 +1,79 @@
+use crate::check::{Decision, Reason};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use warp::http::{Method, Response};
+
+/// One request, as written to stdout when `access_log_json` is set. Health checks also carry the
+/// queue length and decision they reported.
+#[derive(Debug, Serialize)]
+pub struct AccessLogLine {
+    pub timestamp: u64,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub duration_ms: f64,
+    pub client_ip: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_length: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub healthy: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<Reason>,
+}
+
+impl AccessLogLine {
+    /// Handlers that made a health decision leave it in the response's extensions.
+    pub fn new<B>(method: &Method, path: &str, res: &Response<B>, duration: Duration, client: Option<SocketAddr>) -> AccessLogLine {
+        let decision = res.extensions().get::<Decision>();
+        AccessLogLine {
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0),
+            method: method.to_string(),
+            path: path.to_string(),
+            status: res.status().as_u16(),
+            duration_ms: duration.as_secs_f64() * 1000.0,
+            client_ip: client.map(|client| client.ip().to_string()),
+            queue_length: decision.and_then(|decision| decision.queue_length),
+            healthy: decision.map(|decision| decision.healthy),
+            reason: decision.map(|decision| decision.reason),
+        }
+    }
+}
+
+/// Writes the line straight to stdout rather than through `log`, so it stays one bare JSON
+/// object per line whatever the application log format is.
+pub fn write(line: &AccessLogLine) {
+    if let Ok(json) = serde_json::to_string(line) {
+        println!("{}", json);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn includes_the_health_decision() {
+        let mut res = Response::builder().status(503).body(()).unwrap();
+        res.extensions_mut().insert(Decision::new(Some(90), false, Reason::QueueFull));
+        let client = "10.0.0.7:51234".parse().unwrap();
+
+        let line = AccessLogLine::new(&Method::GET, "/health", &res, Duration::from_millis(3), Some(client));
+        let json = serde_json::to_value(&line).unwrap();
+
+        assert_eq!(json["status"], 503);
+        assert_eq!(json["client_ip"], "10.0.0.7");
+        assert_eq!(json["duration_ms"], 3.0);
+        assert_eq!(json["queue_length"], 90);
+        assert_eq!(json["reason"], "queue_full");
+    }
+
+    #[test]
+    fn leaves_out_decision_fields_for_other_endpoints() {
+        let res = Response::builder().status(200).body(()).unwrap();
+        let json = serde_json::to_value(AccessLogLine::new(&Method::GET, "/info", &res, Duration::ZERO, None)).unwrap();
+
+        assert!(json.get("healthy").is_none());
+        assert_eq!(json["client_ip"], serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,171 @@
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An IP network like `10.0.0.0/8`. A bare address is a network of just that address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual-stack socket show up as `::ffff:a.b.c.d`
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => same_prefix(u32::from(network).into(), u32::from(ip).into(), 32, self.prefix_len),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => same_prefix(network.into(), ip.into(), 128, self.prefix_len),
+            _ => false,
+        }
+    }
+}
+
+fn same_prefix(network: u128, ip: u128, bits: u32, prefix_len: u8) -> bool {
+    if prefix_len == 0 {
+        return true;
+    }
+    let shift = bits - u32::from(prefix_len);
+    network >> shift == ip >> shift
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let invalid = || format!("'{}' is not an IP address or CIDR range", s);
+        let (address, prefix_len) = match s.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (s, None),
+        };
+        let network: IpAddr = address.parse().map_err(|_| invalid())?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().ok().filter(|len| *len <= max_len).ok_or_else(invalid)?,
+            None => max_len,
+        };
+        Ok(Cidr { network, prefix_len })
+    }
+}
+
+/// A set of networks, configured as a list or a comma-separated string, so it can come from an
+/// environment variable.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CidrList(Vec<Cidr>);
+
+impl CidrList {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+impl FromStr for CidrList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CidrList, String> {
+        s.split(',').map(str::trim).filter(|cidr| !cidr.is_empty()).map(str::parse).collect::<Result<_, _>>().map(CidrList)
+    }
+}
+
+impl<'de> Deserialize<'de> for CidrList {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<CidrList, D::Error> {
+        struct CidrListVisitor;
+
+        impl<'de> Visitor<'de> for CidrListVisitor {
+            type Value = CidrList;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a list of CIDR ranges")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<CidrList, E> {
+                value.parse().map_err(E::custom)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<CidrList, A::Error> {
+                let mut cidrs = Vec::new();
+                while let Some(cidr) = seq.next_element::<String>()? {
+                    cidrs.push(cidr.parse().map_err(de::Error::custom)?);
+                }
+                Ok(CidrList(cidrs))
+            }
+        }
+
+        deserializer.deserialize_any(CidrListVisitor)
+    }
+}
+
+impl Serialize for CidrList {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter().map(Cidr::to_string))
+    }
+}
+
+/// The address a request really came from. Requests from a `trusted_proxies` address are taken to
+/// be forwarded, and the client is the last `X-Forwarded-For` entry that isn't a trusted proxy
+/// itself; anything before that could have been made up by the client.
+pub fn client_ip(peer: Option<IpAddr>, forwarded_for: Option<&str>, trusted_proxies: &CidrList) -> Option<IpAddr> {
+    let peer = peer?;
+    if !trusted_proxies.contains(peer) {
+        return Some(peer);
+    }
+    let forwarded: Vec<IpAddr> = match forwarded_for {
+        Some(header) => header.split(',').filter_map(|ip| ip.trim().parse().ok()).collect(),
+        None => return Some(peer),
+    };
+    Some(forwarded.into_iter().rev().find(|ip| !trusted_proxies.contains(*ip)).unwrap_or(peer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_addresses_in_range() {
+        let cidrs: CidrList = "10.0.0.0/8, 192.168.1.7, fd00::/8".parse().unwrap();
+        assert!(cidrs.contains(ip("10.1.2.3")));
+        assert!(cidrs.contains(ip("192.168.1.7")));
+        assert!(cidrs.contains(ip("::ffff:10.0.0.1")));
+        assert!(cidrs.contains(ip("fd12::1")));
+        assert!(!cidrs.contains(ip("192.168.1.8")));
+        assert!(!cidrs.contains(ip("11.0.0.1")));
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("localhost".parse::<Cidr>().is_err());
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("8.8.8.8")));
+    }
+
+    #[test]
+    fn serializes_as_a_list_of_ranges() {
+        let cidrs: CidrList = "10.0.0.0/8, 192.168.1.7".parse().unwrap();
+        assert_eq!(serde_json::to_value(&cidrs).unwrap(), serde_json::json!(["10.0.0.0/8", "192.168.1.7/32"]));
+    }
+
+    #[test]
+    fn trusts_forwarded_for_only_from_trusted_proxies() {
+        let proxies: CidrList = "10.0.0.0/8".parse().unwrap();
+        let forwarded = Some("1.2.3.4, 203.0.113.9, 10.0.0.2");
+
+        assert_eq!(client_ip(Some(ip("10.0.0.1")), forwarded, &proxies), Some(ip("203.0.113.9")));
+        assert_eq!(client_ip(Some(ip("198.51.100.1")), forwarded, &proxies), Some(ip("198.51.100.1")));
+        assert_eq!(client_ip(Some(ip("10.0.0.1")), None, &proxies), Some(ip("10.0.0.1")));
+    }
+}
@@ -0,0 +1,1207 @@
+use crate::error::QueueError;
+use crate::passenger::{
+    lists_process_sessions, parse_app_queues, parse_idle_process_count, parse_in_flight, parse_max_pool_size, parse_process_count, parse_process_memory,
+    parse_queue_length_with, queue_patterns, StatusCache,
+};
+use crate::settings::{CapacityMetric, ComparisonDirection, InstancePolicy, NoInstances, Settings};
+use futures_util::future;
+use log::{info, warn};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Mutex, OnceLock,
+};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::time::timeout;
+
+// How long `app_health_url` gets to answer. Kept well under `check_deadline_ms`, since a hanging app
+// should fail readiness on its own rather than via the deadline.
+const APP_HEALTH_TIMEOUT: Duration = Duration::from_secs(2);
+// Where the `ramp_duration_secs` warmup ramp starts, as a fraction of `queue_threshold_ratio`. Not
+// 0, which would turn away even an empty queue.
+const RAMP_START_FRACTION: f64 = 0.1;
+
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+/// Why a check came out the way it did.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    Ok,
+    QueueFull,
+    PassengerDown,
+    WarmingUp,
+    DeadlineExceeded,
+    NotRunning,
+    Forced,
+    PoolExhausted,
+    AtCapacity,
+    AppUnhealthy,
+    LowScore,
+    Unstable,
+}
+
+impl Reason {
+    /// The reason's name as it appears in JSON, the `X-Health-Reason` header and `/readyz` bodies.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Reason::Ok => "ok",
+            Reason::QueueFull => "queue_full",
+            Reason::PassengerDown => "passenger_down",
+            Reason::WarmingUp => "warming_up",
+            Reason::DeadlineExceeded => "deadline_exceeded",
+            Reason::NotRunning => "not_running",
+            Reason::Forced => "forced",
+            Reason::PoolExhausted => "pool_exhausted",
+            Reason::AtCapacity => "at_capacity",
+            Reason::AppUnhealthy => "app_unhealthy",
+            Reason::LowScore => "low_score",
+            Reason::Unstable => "unstable",
+        }
+    }
+}
+
+/// The outcome of a single health check.
+#[derive(Debug, Clone, Serialize)]
+pub struct Decision {
+    pub timestamp: u64,
+    pub queue_length: Option<i32>,
+    pub healthy: bool,
+    pub reason: Reason,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<Score>,
+    /// Healthy, but past `degraded_memory_mb` or `degraded_queue_ratio`, so worth less traffic.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub degraded: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub capacity: Option<Capacity>,
+}
+
+/// The `scoring` score a decision was made on, and the signals that went into it. Each is 0–100,
+/// with 100 the most room. Signals without a weight, or that passenger-status didn't show, are
+/// left out.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Score {
+    pub score: f64,
+    pub queue: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle: Option<f64>,
+}
+
+/// What passenger-status showed of the host's capacity, for `capacity_report`. Anything it didn't
+/// show is left out rather than reported as 0.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Capacity {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue: Option<i32>,
+    pub max_queue: i32,
+    /// `capacity_headroom` of the queue.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub headroom_pct: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub processes: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle: Option<i32>,
+}
+
+impl Capacity {
+    fn new(output: &str, queue_length: Option<i32>, settings: &Settings) -> Capacity {
+        Capacity {
+            queue: queue_length,
+            max_queue: settings.max_queue_length,
+            headroom_pct: queue_length.map(|queue_length| capacity_headroom(queue_length, settings)),
+            processes: parse_process_count(output).ok(),
+            idle: parse_idle_process_count(output).ok().filter(|_| lists_process_sessions(output)),
+        }
+    }
+}
+
+impl Score {
+    /// The weighted average of the signals there are, or just the queue's if none have a weight.
+    fn new(output: &str, queue_length: i32, settings: &Settings) -> Score {
+        let scoring = &settings.scoring;
+        let queue = 100.0 * (1.0 - (f64::from(queue_length) / f64::from(settings.max_queue_length.max(1))).clamp(0.0, 1.0));
+        let memory = match (scoring.memory_weight > 0.0, settings.max_process_memory_mb) {
+            (true, Some(limit)) => {
+                let largest = parse_process_memory(output).into_iter().map(|(_, megabytes)| megabytes).max();
+                largest.map(|largest| 100.0 * (1.0 - (largest as f64 / limit as f64).min(1.0)))
+            }
+            _ => None,
+        };
+        let idle = match (scoring.idle_weight > 0.0, parse_idle_process_count(output), parse_process_count(output)) {
+            (true, Ok(idle), Ok(processes)) if processes > 0 => Some(100.0 * f64::from(idle) / f64::from(processes)),
+            _ => None,
+        };
+
+        let signals = [(scoring.queue_weight, Some(queue)), (scoring.memory_weight, memory), (scoring.idle_weight, idle)];
+        let (weighted, weights) = signals
+            .iter()
+            .filter_map(|(weight, signal)| signal.map(|signal| (weight * signal, *weight)))
+            .fold((0.0, 0.0), |(total, weights), (weighted, weight)| (total + weighted, weights + weight));
+        let score = if weights > 0.0 { weighted / weights } else { queue };
+        Score { score, queue, memory, idle }
+    }
+}
+
+impl Decision {
+    pub fn new(queue_length: Option<i32>, healthy: bool, reason: Reason) -> Decision {
+        Decision {
+            timestamp: unix_timestamp(),
+            queue_length,
+            healthy,
+            reason,
+            error: None,
+            score: None,
+            degraded: false,
+            capacity: None,
+        }
+    }
+
+    /// Decides on a queue reading. A full queue is always unhealthy; a failure to measure the
+    /// queue is unhealthy unless `fail_open` is set, in which case it's reported healthy.
+    /// Passenger not running at all is handled as `no_instances` says.
+    pub fn from_queue(queue_length: Result<i32, QueueError>, settings: &Settings) -> Decision {
+        match queue_length {
+            Ok(queue_length) if queue_has_room(queue_length, settings) => Decision::new(Some(queue_length), true, Reason::Ok),
+            Ok(queue_length) => Decision::new(Some(queue_length), false, Reason::QueueFull),
+            Err(QueueError::NotRunning) if settings.no_instances == NoInstances::Healthy => {
+                Decision::new(None, true, Reason::NotRunning)
+            }
+            Err(QueueError::NotRunning) if settings.no_instances == NoInstances::Stopped => {
+                Decision::new(None, false, Reason::NotRunning)
+            }
+            // A safety valve, not a passenger failure, so `fail_open` doesn't apply
+            Err(e @ QueueError::SpawnLimit { .. }) => Decision::unmeasured(&e, false),
+            Err(e) => {
+                if settings.fail_open {
+                    warn!("Could not measure passenger queue, failing open: {}", e);
+                }
+                Decision::unmeasured(&e, settings.fail_open)
+            }
+        }
+    }
+
+    /// Like `from_queue`, but on how much of the capacity `metric` measures is in use, with
+    /// `queue_threshold_ratio` of it as the threshold and compared as `comparison_direction` says.
+    /// Capacity is one request per process, plus `max_queue_length` for `combined`.
+    fn from_capacity(output: &str, metric: CapacityMetric, settings: &Settings) -> Decision {
+        let measured = queue_length(output, settings).and_then(|queue_length| {
+            let in_flight = parse_in_flight(output)?;
+            let processes = parse_process_count(output)?;
+            Ok((queue_length, in_flight, processes))
+        });
+        let (queue_length, in_flight, processes) = match measured {
+            Ok(measured) => measured,
+            Err(e) => return Decision::from_queue(Err(e), settings),
+        };
+        let (used, capacity) = match metric {
+            CapacityMetric::Combined => (queue_length + in_flight, processes + settings.max_queue_length),
+            _ => (in_flight, processes),
+        };
+        if settings.log_queue_value {
+            info!("Passenger is handling {} of {} requests it has capacity for", used, capacity);
+        }
+        if passes(used, capacity_threshold(capacity, settings), settings.comparison_direction) {
+            Decision::new(Some(queue_length), true, Reason::Ok)
+        } else {
+            Decision::new(Some(queue_length), false, Reason::AtCapacity)
+        }
+    }
+
+    /// Like `from_queue`, but healthy while the `scoring` score is at least `min_healthy_score`.
+    pub(crate) fn from_score(output: &str, settings: &Settings) -> Decision {
+        let queue_length = match queue_length(output, settings) {
+            Ok(queue_length) => queue_length,
+            Err(e) => return Decision::from_queue(Err(e), settings),
+        };
+        let score = Score::new(output, queue_length, settings);
+        if settings.log_queue_value {
+            info!("Passenger health score is {:.1} of a minimum {}", score.score, settings.scoring.min_healthy_score);
+        }
+        let healthy = score.score >= settings.scoring.min_healthy_score;
+        let reason = if healthy { Reason::Ok } else { Reason::LowScore };
+        Decision { score: Some(score), ..Decision::new(Some(queue_length), healthy, reason) }
+    }
+
+    pub(crate) fn unmeasured(error: &QueueError, healthy: bool) -> Decision {
+        Decision {
+            error: Some(error.to_string()),
+            ..Decision::new(None, healthy, Reason::PassengerDown)
+        }
+    }
+
+    pub(crate) fn deadline_exceeded(settings: &Settings) -> Decision {
+        warn!("Health check didn't finish within check_deadline_ms ({}ms)", settings.check_deadline_ms);
+        Decision::new(None, false, Reason::DeadlineExceeded)
+    }
+
+    /// A human-readable summary, for the command line modes.
+    pub fn describe(&self) -> String {
+        match self.reason {
+            Reason::Ok => "passenger can take more traffic".to_string(),
+            Reason::QueueFull => "passenger queue is full".to_string(),
+            Reason::PassengerDown => format!(
+                "could not measure passenger queue: {}",
+                self.error.as_deref().unwrap_or("unknown error")
+            ),
+            Reason::WarmingUp => "passenger is warming up".to_string(),
+            Reason::DeadlineExceeded => "health check took too long".to_string(),
+            Reason::NotRunning => "passenger is not running".to_string(),
+            Reason::Forced if self.healthy => "forced healthy by force_healthy".to_string(),
+            Reason::Forced => "forced unhealthy by force_unhealthy".to_string(),
+            Reason::PoolExhausted => "passenger is running every process it can and requests are queueing".to_string(),
+            Reason::AtCapacity => "passenger is handling as many requests as it has capacity for".to_string(),
+            Reason::AppUnhealthy => format!(
+                "app_health_url is failing: {}",
+                self.error.as_deref().unwrap_or("unknown error")
+            ),
+            Reason::Unstable => "passenger's process count keeps changing, workers may be crash-looping".to_string(),
+            Reason::LowScore => match &self.score {
+                Some(score) => format!("passenger health score {:.1} is below min_healthy_score", score.score),
+                None => "passenger health score is below min_healthy_score".to_string(),
+            },
+        }
+    }
+}
+
+/// Checks whether passenger's top-level queue has room for more traffic.
+pub async fn can_take_more_traffic(settings: &Settings, status: &Arc<StatusCache>) -> Decision {
+    if let Some(decision) = forced(settings) {
+        return decision;
+    }
+    decide(status.read(settings).await, settings, status)
+}
+
+/// `/health`'s decision on `output`, as read from `status`, or on the failure to read it.
+pub(crate) fn decide(output: Result<String, QueueError>, settings: &Settings, status: &StatusCache) -> Decision {
+    if let Some(decision) = forced(settings) {
+        return decision;
+    }
+    let output = match output {
+        Ok(output) => output,
+        Err(e) => return Decision::from_queue(Err(e), settings),
+    };
+    let settings = &penalized(settings, stale_penalty(settings, status));
+    let decision = match settings.capacity_metric {
+        _ if settings.scoring.enabled() => Decision::from_score(&output, settings),
+        CapacityMetric::Queue => Decision::from_queue(queue_length(&output, settings), settings),
+        metric => Decision::from_capacity(&output, metric, settings),
+    };
+    let decision = match (decision.reason, decision.queue_length, settings.full_pool_queue_threshold) {
+        (Reason::Ok, Some(queue_length), Some(threshold)) if queue_length > threshold && pool_is_full(&output) => {
+            Decision::new(Some(queue_length), false, Reason::PoolExhausted)
+        }
+        _ => decision,
+    };
+    let mut decision = mark_degraded(decision, &output, settings);
+    if settings.capacity_report {
+        decision.capacity = Some(Capacity::new(&output, decision.queue_length, settings));
+    }
+    decision
+}
+
+/// Marks a healthy decision degraded when a process uses more than `degraded_memory_mb` or the
+/// queue is at `degraded_queue_ratio` of `max_queue_length` or more. Degraded hosts still pass.
+pub(crate) fn mark_degraded(mut decision: Decision, output: &str, settings: &Settings) -> Decision {
+    if !decision.healthy {
+        return decision;
+    }
+    let over_memory = settings
+        .degraded_memory_mb
+        .is_some_and(|limit| parse_process_memory(output).iter().any(|(_, megabytes)| *megabytes > limit));
+    let long_queue = match (settings.degraded_queue_ratio, decision.queue_length) {
+        (Some(ratio), Some(queue_length)) => f64::from(queue_length) >= f64::from(settings.max_queue_length) * ratio,
+        _ => false,
+    };
+    decision.degraded = over_memory || long_queue;
+    decision
+}
+
+/// `can_take_more_traffic` for several Passenger instances, combined as `instance_policy` says.
+pub async fn can_all_take_more_traffic<'a>(settings: &Settings, statuses: impl IntoIterator<Item = &'a Arc<StatusCache>>) -> Decision {
+    let decisions = future::join_all(statuses.into_iter().map(|status| can_take_more_traffic(settings, status))).await;
+    combine(decisions, settings.instance_policy)
+}
+
+/// The decision that speaks for all of `decisions`: the first unhealthy one, or else the one with
+/// the longest queue. `worst` leaves out instances without a queue length, unless none have one.
+pub fn combine(decisions: Vec<Decision>, policy: InstancePolicy) -> Decision {
+    let any_measured = decisions.iter().any(|decision| decision.queue_length.is_some());
+    let mut considered: Vec<Decision> = decisions
+        .into_iter()
+        .filter(|decision| policy == InstancePolicy::All || !any_measured || decision.queue_length.is_some())
+        .collect();
+    match considered.iter().position(|decision| !decision.healthy) {
+        Some(unhealthy) => considered.swap_remove(unhealthy),
+        None => considered.into_iter().max_by_key(|decision| decision.queue_length).expect("at least one instance"),
+    }
+}
+
+/// How far `queue_length` is on the healthy side of the threshold, as a percentage of the threshold:
+/// for `below`, 100 for an empty queue, 0 at or over the threshold; for `above`, 0 at or under the
+/// threshold, 100 at twice it or more.
+pub fn capacity_headroom(queue_length: i32, settings: &Settings) -> u32 {
+    let threshold = queue_threshold(settings);
+    if threshold <= 0.0 {
+        return 0;
+    }
+    let beyond = match settings.comparison_direction {
+        ComparisonDirection::Below => threshold - queue_length as f32,
+        ComparisonDirection::Above => queue_length as f32 - threshold,
+    };
+    (beyond / threshold * 100.0).clamp(0.0, 100.0) as u32
+}
+
+/// Whether passenger is running as many processes as `Max pool size` allows, so it can't start
+/// more to work through its queue.
+fn pool_is_full(output: &str) -> bool {
+    match (parse_process_count(output), parse_max_pool_size(output)) {
+        (Ok(processes), Ok(max_pool_size)) => processes >= max_pool_size,
+        _ => false,
+    }
+}
+
+/// The decision `force_unhealthy` or `force_healthy` dictate, if either is set, for bringing up a
+/// load balancer without Passenger. `force_unhealthy` wins if both are.
+pub fn forced(settings: &Settings) -> Option<Decision> {
+    if settings.force_unhealthy {
+        Some(Decision::new(None, false, Reason::Forced))
+    } else if settings.force_healthy {
+        Some(Decision::new(None, true, Reason::Forced))
+    } else {
+        None
+    }
+}
+
+/// Whether `queue_length` is below `queue_threshold_ratio` of `max_queue_length`, or above it when
+/// `comparison_direction` is `above`.
+pub fn queue_has_room(queue_length: i32, settings: &Settings) -> bool {
+    if settings.log_queue_value {
+        info!(
+            "Passenger queue length {} of max {} ({:.0}%)",
+            queue_length,
+            settings.max_queue_length,
+            queue_length as f32 / settings.max_queue_length as f32 * 100.0
+        );
+    }
+    passes(queue_length, queue_threshold(settings), settings.comparison_direction)
+}
+
+/// Whether `value` is on the healthy side of `threshold` for `direction`.
+fn passes(value: i32, threshold: f32, direction: ComparisonDirection) -> bool {
+    match direction {
+        ComparisonDirection::Below => (value as f32) < threshold,
+        ComparisonDirection::Above => (value as f32) > threshold,
+    }
+}
+
+/// The queue length `queue_has_room` compares against: `queue_threshold_ratio` of `max_queue_length`.
+pub fn queue_threshold(settings: &Settings) -> f32 {
+    capacity_threshold(settings.max_queue_length, settings)
+}
+
+fn capacity_threshold(capacity: i32, settings: &Settings) -> f32 {
+    capacity as f32 * effective_threshold_ratio(settings) as f32
+}
+
+/// `queue_threshold_ratio`, ramped from strict to its configured value over the first
+/// `ramp_duration_secs` after startup, so a host that just came up isn't sent its full share at once.
+pub fn effective_threshold_ratio(settings: &Settings) -> f64 {
+    let ramp = Duration::from_secs(settings.ramp_duration_secs);
+    ramped(settings.queue_threshold_ratio, ramp, started().elapsed(), settings.comparison_direction)
+}
+
+/// Starts from `RAMP_START_FRACTION` of `ratio` for `below`, and as many times `ratio` for `above`,
+/// where a higher threshold is the stricter one. So it's only strict through comparisons that follow
+/// `comparison_direction`, like `passes`.
+fn ramped(ratio: f64, ramp: Duration, elapsed: Duration, direction: ComparisonDirection) -> f64 {
+    if elapsed >= ramp {
+        return ratio;
+    }
+    let fraction = (elapsed.as_secs_f64() / ramp.as_secs_f64()).max(RAMP_START_FRACTION);
+    match direction {
+        ComparisonDirection::Below => ratio * fraction,
+        ComparisonDirection::Above => ratio / fraction,
+    }
+}
+
+/// How much to take off the threshold for `status`' output being old, with `max_staleness_secs`: from
+/// 0 for output just read, rising steadily to 1, shedding everything, once it's `max_staleness_secs`
+/// old. So a poller that falls behind makes the host more cautious gradually rather than all at once.
+pub fn stale_penalty(settings: &Settings, status: &StatusCache) -> f64 {
+    match (settings.max_staleness_secs.filter(|secs| *secs > 0), status.last_fetched()) {
+        (Some(max_staleness), Some(fetched_at)) => penalty_for_age(fetched_at.elapsed(), Duration::from_secs(max_staleness)),
+        _ => 0.0,
+    }
+}
+
+fn penalty_for_age(age: Duration, max_staleness: Duration) -> f64 {
+    (age.as_secs_f64() / max_staleness.as_secs_f64()).min(1.0)
+}
+
+/// `settings` with `queue_threshold_ratio` scaled down by `penalty`, for deciding on old output. Only
+/// makes sense for `comparison_direction = below`, which loading settings insists on.
+pub(crate) fn penalized(settings: &Settings, penalty: f64) -> Cow<'_, Settings> {
+    if penalty <= 0.0 {
+        return Cow::Borrowed(settings);
+    }
+    Cow::Owned(Settings { queue_threshold_ratio: settings.queue_threshold_ratio * (1.0 - penalty), ..settings.clone() })
+}
+
+/// When the process started, as far as the warmup ramp is concerned: the first call, which `run`
+/// makes before serving anything.
+pub fn started() -> Instant {
+    *STARTED.get_or_init(Instant::now)
+}
+
+/// The top-level queue length passenger-status shows, times `queue_scale`, so queues measured in
+/// other units compare against `max_queue_length` like Passenger's.
+pub fn queue_length(output: &str, settings: &Settings) -> Result<i32, QueueError> {
+    parse_queue_length_with(output, &queue_patterns(&settings.queue_patterns)).map(|queue_length| scale_queue(queue_length, settings))
+}
+
+fn scale_queue(queue_length: i32, settings: &Settings) -> i32 {
+    if settings.queue_scale == 1.0 {
+        return queue_length;
+    }
+    (f64::from(queue_length) * settings.queue_scale).round() as i32
+}
+
+pub async fn get_queue_length(settings: &Settings, status: &Arc<StatusCache>) -> Result<i32, QueueError> {
+    queue_length(&status.read(settings).await?, settings)
+}
+
+pub(crate) fn check_deadline(settings: &Settings) -> Duration {
+    Duration::from_millis(settings.check_deadline_ms)
+}
+
+/// Bounds a whole check, however long its individual steps take.
+pub(crate) async fn within_deadline(settings: &Settings, check: impl Future<Output = Decision>) -> Decision {
+    timeout(check_deadline(settings), check)
+        .await
+        .unwrap_or_else(|_| Decision::deadline_exceeded(settings))
+}
+
+/// Checks a single app group's queue, or returns `None` if passenger doesn't know the app.
+pub async fn check_app(settings: &Settings, status: &Arc<StatusCache>, app: &str) -> Option<Decision> {
+    if let Some(decision) = forced(settings) {
+        return Some(decision);
+    }
+    let queue_length = match status.read(settings).await.and_then(|output| parse_app_queues(&output)) {
+        Ok(queues) => Ok(scale_queue(*queues.get(app)?, settings)),
+        Err(e) => Err(e),
+    };
+    Some(Decision::from_queue(queue_length, &penalized(settings, stale_penalty(settings, status))))
+}
+
+/// Checks readiness like `check_passenger_readiness`, then, once Passenger's ready, that its process
+/// count isn't changing more than `max_process_churn` allows and that the app itself answers
+/// `app_health_url`.
+pub async fn check_readiness(settings: &Settings, status: &Arc<StatusCache>, warmup: &Warmup) -> Decision {
+    let decision = check_passenger_readiness(settings, status, warmup).await;
+    if let Some(max_churn) = settings.max_process_churn {
+        let churn = status.process_churn(settings);
+        if decision.healthy && churn > max_churn && forced(settings).is_none() {
+            warn!("Passenger's process count changed {} times in process_churn_window_secs, more than max_process_churn", churn);
+            return Decision::new(decision.queue_length, false, Reason::Unstable);
+        }
+    }
+    let url = match settings.app_health_url.as_deref() {
+        Some(url) if !url.is_empty() && decision.healthy && forced(settings).is_none() => url,
+        _ => return decision,
+    };
+    match app_responds(url).await {
+        Ok(()) => decision,
+        Err(e) => {
+            warn!("app_health_url {} is failing: {}", url, e);
+            Decision { error: Some(e), ..Decision::new(decision.queue_length, false, Reason::AppUnhealthy) }
+        }
+    }
+}
+
+/// `GET`s `url`, expecting a 2xx within `APP_HEALTH_TIMEOUT`.
+async fn app_responds(url: &str) -> Result<(), String> {
+    let uri: hyper::Uri = url.parse().map_err(|e| format!("invalid URL: {}", e))?;
+    let res = timeout(APP_HEALTH_TIMEOUT, hyper::Client::new().get(uri))
+        .await
+        .map_err(|_| format!("no response within {}ms", APP_HEALTH_TIMEOUT.as_millis()))?
+        .map_err(|e| e.to_string())?;
+    if res.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("status {}", res.status()))
+    }
+}
+
+/// Like `/health`, but stays unavailable until passenger reports at least `min_ready_processes`
+/// running processes and `healthy_streak_required` checks in a row have passed. Warming up needs a
+/// successful reading, so `fail_open` doesn't apply to it.
+async fn check_passenger_readiness(settings: &Settings, status: &Arc<StatusCache>, warmup: &Warmup) -> Decision {
+    if warmup.is_done() || forced(settings).is_some() {
+        return can_take_more_traffic(settings, status).await;
+    }
+
+    let output = match status.read(settings).await {
+        Ok(output) => output,
+        Err(e) => {
+            warn!("Could not read passenger status while warming up: {}", e);
+            warmup.failed();
+            return Decision::unmeasured(&e, false);
+        }
+    };
+    let processes = parse_process_count(&output).ok();
+    if settings.min_ready_processes > 0 && processes.is_none_or(|processes| processes < settings.min_ready_processes) {
+        warmup.failed();
+        return Decision::new(None, false, Reason::WarmingUp);
+    }
+
+    let decision = decide(Ok(output), settings, status);
+    // Only a reading counts towards the streak, not a `fail_open` pass
+    if !decision.healthy || decision.queue_length.is_none() {
+        warmup.failed();
+        return decision;
+    }
+    if warmup.passed(settings.healthy_streak_required) {
+        decision
+    } else {
+        Decision::new(decision.queue_length, false, Reason::WarmingUp)
+    }
+}
+
+/// Where `/readyz` is in warming up: until passenger has `min_ready_processes` processes and then
+/// passes `healthy_streak_required` checks in a row. Once over it stays over, so `/readyz` never goes
+/// back to warming up.
+#[derive(Debug, Default)]
+pub struct Warmup {
+    done: AtomicBool,
+    streak: AtomicUsize,
+}
+
+impl Warmup {
+    /// Already over when neither setting asks for anything.
+    pub fn new(settings: &Settings) -> Warmup {
+        let done = settings.min_ready_processes <= 0 && settings.healthy_streak_required <= 1;
+        Warmup { done: AtomicBool::new(done), streak: AtomicUsize::new(0) }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done.load(Ordering::SeqCst)
+    }
+
+    /// Counts a passing check, ending warmup if it makes `required` in a row.
+    fn passed(&self, required: usize) -> bool {
+        let streak = self.streak.fetch_add(1, Ordering::SeqCst) + 1;
+        if streak < required {
+            info!("Warming up, {} of {} checks in a row passed", streak, required);
+            return false;
+        }
+        info!("Passenger passed {} checks in a row, no longer warming up", streak);
+        self.done.store(true, Ordering::SeqCst);
+        true
+    }
+
+    fn failed(&self) {
+        self.streak.store(0, Ordering::SeqCst);
+    }
+}
+
+/// Recent `/health` results, so a single bad sample doesn't take the host out of rotation:
+/// an unhealthy decision is only reported once `unhealthy_samples_required` of the last
+/// `sample_window` checks were unhealthy. Until then it's reported healthy, keeping its reason.
+#[derive(Default)]
+pub struct SampleWindow {
+    samples: Mutex<VecDeque<bool>>,
+}
+
+impl SampleWindow {
+    pub fn new() -> SampleWindow {
+        SampleWindow::default()
+    }
+
+    pub fn hold(&self, mut decision: Decision, settings: &Settings) -> Decision {
+        let window = settings.sample_window.max(1);
+        let mut samples = self.samples.lock().unwrap();
+        samples.push_back(decision.healthy);
+        while samples.len() > window {
+            samples.pop_front();
+        }
+
+        // More samples than the window holds could never be reached, so the whole window is the most we ask for
+        let required = settings.unhealthy_samples_required.min(window);
+        let unhealthy = samples.iter().filter(|healthy| !**healthy).count();
+        if !decision.healthy && unhealthy < required {
+            info!("Holding off on reporting unhealthy, {} of the last {} checks failed", unhealthy, samples.len());
+            decision.healthy = true;
+        }
+        decision
+    }
+}
+
+/// Watches `passenger_pid_file` and, for `reload_grace_secs` after its contents change, reports
+/// unhealthy decisions as healthy, keeping their reason. Passenger's numbers are unreliable while it
+/// restarts, and shedding traffic over them would only make a deploy worse.
+#[derive(Default)]
+pub struct ReloadGrace {
+    watched: Mutex<WatchedPid>,
+}
+
+#[derive(Default)]
+struct WatchedPid {
+    // `None` until the file has been read once; a missing file reads as `Some(None)`
+    pid: Option<Option<String>>,
+    changed_at: Option<Instant>,
+}
+
+impl ReloadGrace {
+    pub fn new() -> ReloadGrace {
+        ReloadGrace::default()
+    }
+
+    pub fn hold(&self, mut decision: Decision, settings: &Settings) -> Decision {
+        let path = match settings.passenger_pid_file.as_deref() {
+            Some(path) if !path.is_empty() => path,
+            _ => return decision,
+        };
+        let pid = std::fs::read_to_string(path).ok().map(|pid| pid.trim().to_string());
+        let mut watched = self.watched.lock().unwrap();
+        if watched.pid.as_ref().is_some_and(|last| *last != pid) {
+            info!("{} changed, Passenger is reloading", path);
+            watched.changed_at = Some(Instant::now());
+        }
+        watched.pid = Some(pid);
+
+        let grace = Duration::from_secs(settings.reload_grace_secs);
+        let reloading = watched.changed_at.is_some_and(|changed_at| changed_at.elapsed() < grace);
+        if !decision.healthy && reloading {
+            info!("Passenger is reloading, reporting healthy for now: {}", decision.describe());
+            decision.healthy = true;
+        }
+        decision
+    }
+}
+
+/// Only runs every `check_every_n_requests`th `/health` check, answering the ones in between with
+/// the previous decision. Unlike `stale_after_ms`, this bounds passenger-status runs by probe
+/// count, so a burst of probes still can't run it more than once per N of them.
+#[derive(Default)]
+pub struct EveryNthCheck {
+    requests: AtomicUsize,
+    last: Mutex<Option<Decision>>,
+}
+
+impl EveryNthCheck {
+    pub fn new() -> EveryNthCheck {
+        EveryNthCheck::default()
+    }
+
+    pub async fn decide(&self, settings: &Settings, check: impl Future<Output = Decision>) -> Decision {
+        let every = settings.check_every_n_requests.max(1);
+        if !self.requests.fetch_add(1, Ordering::Relaxed).is_multiple_of(every) {
+            if let Some(decision) = self.last.lock().unwrap().clone() {
+                return decision;
+            }
+        }
+        let decision = check.await;
+        *self.last.lock().unwrap() = Some(decision.clone());
+        decision
+    }
+}
+
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Scoring;
+
+    fn holding(unhealthy_samples_required: usize, sample_window: usize) -> Settings {
+        Settings { unhealthy_samples_required, sample_window, ..Settings::default() }
+    }
+
+    #[test]
+    fn single_bad_sample_is_held_when_more_are_required() {
+        let samples = SampleWindow::new();
+        let settings = holding(2, 3);
+        let full = || Decision::new(Some(90), false, Reason::QueueFull);
+
+        assert!(samples.hold(Decision::new(Some(0), true, Reason::Ok), &settings).healthy);
+        let held = samples.hold(full(), &settings);
+        assert!(held.healthy);
+        assert_eq!(held.reason, Reason::QueueFull);
+        assert!(!samples.hold(full(), &settings).healthy);
+    }
+
+    #[test]
+    fn bad_samples_outside_the_window_do_not_count() {
+        let samples = SampleWindow::new();
+        let settings = holding(2, 2);
+        let ok = || Decision::new(Some(0), true, Reason::Ok);
+        let full = || Decision::new(Some(90), false, Reason::QueueFull);
+
+        assert!(samples.hold(full(), &settings).healthy);
+        assert!(samples.hold(ok(), &settings).healthy);
+        assert!(samples.hold(full(), &settings).healthy);
+    }
+
+    #[test]
+    fn bad_samples_are_reported_straight_away_by_default() {
+        let samples = SampleWindow::new();
+        assert!(!samples.hold(Decision::new(Some(90), false, Reason::QueueFull), &Settings::default()).healthy);
+    }
+
+    #[test]
+    fn unhealthy_decisions_are_held_while_passenger_reloads() {
+        let path = std::env::temp_dir().join(format!("passenger-ready-{}-passenger.pid", std::process::id()));
+        std::fs::write(&path, "100\n").unwrap();
+        let grace = ReloadGrace::new();
+        let settings = Settings { passenger_pid_file: Some(path.to_str().unwrap().to_string()), ..Settings::default() };
+        let full = || Decision::new(Some(90), false, Reason::QueueFull);
+
+        assert!(!grace.hold(full(), &settings).healthy);
+        std::fs::write(&path, "200\n").unwrap();
+        let held = grace.hold(full(), &settings);
+        assert!(held.healthy);
+        assert_eq!(held.reason, Reason::QueueFull);
+
+        let expired = Settings { reload_grace_secs: 0, ..settings };
+        assert!(!grace.hold(full(), &expired).healthy);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn checks_only_every_nth_request() {
+        let every_nth = EveryNthCheck::new();
+        let settings = Settings { check_every_n_requests: 3, ..Settings::default() };
+
+        let mut queue_lengths = Vec::new();
+        for queue_length in 0..5 {
+            let decision = every_nth.decide(&settings, async { Decision::new(Some(queue_length), true, Reason::Ok) }).await;
+            queue_lengths.push(decision.queue_length);
+        }
+        assert_eq!(queue_lengths, vec![Some(0), Some(0), Some(0), Some(3), Some(3)]);
+    }
+
+    #[test]
+    fn reason_names_match_their_json() {
+        for reason in [
+            Reason::Ok,
+            Reason::QueueFull,
+            Reason::PassengerDown,
+            Reason::WarmingUp,
+            Reason::DeadlineExceeded,
+            Reason::NotRunning,
+            Reason::Forced,
+            Reason::PoolExhausted,
+            Reason::AtCapacity,
+            Reason::AppUnhealthy,
+            Reason::LowScore,
+            Reason::Unstable,
+        ] {
+            assert_eq!(serde_json::to_value(reason).unwrap(), reason.as_str());
+        }
+    }
+
+    fn pool_of(processes: i32, queue_length: i32) -> Arc<StatusCache> {
+        let output = format!("Max pool size : 6\nProcesses : {}\nRequests in top-level queue : {}\n", processes, queue_length);
+        Arc::new(StatusCache::new(move || {
+            let output = output.clone();
+            async move { Ok(output) }
+        }))
+    }
+
+    #[tokio::test]
+    async fn scales_the_queue_before_comparing_it() {
+        let scaled = |queue_scale: f64| Settings { max_queue_length: 100, queue_scale, ..Settings::default() };
+
+        let decision = can_take_more_traffic(&scaled(1.0), &pool_of(6, 50)).await;
+        assert_eq!((decision.healthy, decision.queue_length), (true, Some(50)));
+        let decision = can_take_more_traffic(&scaled(2.5), &pool_of(6, 50)).await;
+        assert_eq!((decision.healthy, decision.queue_length), (false, Some(125)));
+        assert_eq!(can_take_more_traffic(&scaled(0.01), &pool_of(6, 50)).await.queue_length, Some(1));
+    }
+
+    #[tokio::test]
+    async fn full_pool_with_a_queue_is_unhealthy_when_enabled() {
+        let settings = Settings { full_pool_queue_threshold: Some(5), ..Settings::default() };
+
+        let decision = can_take_more_traffic(&settings, &pool_of(6, 12)).await;
+        assert!(!decision.healthy);
+        assert_eq!(decision.reason, Reason::PoolExhausted);
+
+        assert!(can_take_more_traffic(&settings, &pool_of(6, 5)).await.healthy);
+        assert!(can_take_more_traffic(&settings, &pool_of(4, 12)).await.healthy);
+        assert!(can_take_more_traffic(&Settings::default(), &pool_of(6, 12)).await.healthy);
+    }
+
+    #[tokio::test]
+    async fn scores_weighted_signals_when_configured() {
+        let settings = Settings {
+            scoring: Scoring { queue_weight: 1.0, memory_weight: 1.0, idle_weight: 2.0, min_healthy_score: 50.0 },
+            max_process_memory_mb: Some(500),
+            ..Settings::default()
+        };
+        let check = |output: &'static str| {
+            let settings = settings.clone();
+            async move { can_take_more_traffic(&settings, &Arc::new(StatusCache::new(move || async move { Ok(output.to_string()) }))).await }
+        };
+
+        // 12 of 100 queued, but no process is idle
+        let busy = check(include_str!("../samples/passenger-5-busy.txt")).await;
+        let score = busy.score.clone().unwrap();
+        assert_eq!((score.queue, score.idle), (88.0, Some(0.0)));
+        assert!(score.memory.unwrap() < 25.0);
+        assert!(score.score < 50.0);
+        assert_eq!((busy.healthy, busy.reason), (false, Reason::LowScore));
+
+        let idle = check(include_str!("../samples/passenger-6-idle.txt")).await;
+        let score = idle.score.clone().unwrap();
+        assert_eq!((score.queue, score.idle), (100.0, Some(100.0)));
+        assert!((score.memory.unwrap() - 69.6).abs() < 0.01);
+        assert!(idle.healthy);
+
+        assert_eq!(check_capacity(CapacityMetric::Queue, include_str!("../samples/passenger-5-busy.txt")).await.score, None);
+    }
+
+    async fn check_capacity(metric: CapacityMetric, output: &'static str) -> Decision {
+        let status = Arc::new(StatusCache::new(move || async move { Ok(output.to_string()) }));
+        can_take_more_traffic(&Settings { capacity_metric: metric, ..Settings::default() }, &status).await
+    }
+
+    #[tokio::test]
+    async fn in_flight_requests_count_against_process_capacity() {
+        // Every one of the six processes is busy, with 12 requests queued
+        let busy = include_str!("../samples/passenger-5-busy.txt");
+        assert!(check_capacity(CapacityMetric::Queue, busy).await.healthy);
+
+        let decision = check_capacity(CapacityMetric::Inflight, busy).await;
+        assert!(!decision.healthy);
+        assert_eq!(decision.reason, Reason::AtCapacity);
+        assert_eq!(decision.queue_length, Some(12));
+
+        // 18 of 106
+        assert!(check_capacity(CapacityMetric::Combined, busy).await.healthy);
+        assert!(check_capacity(CapacityMetric::Inflight, include_str!("../samples/passenger-6-idle.txt")).await.healthy);
+    }
+
+    #[tokio::test]
+    async fn capacity_metrics_follow_the_comparison_direction() {
+        let settings = Settings {
+            capacity_metric: CapacityMetric::Inflight,
+            comparison_direction: ComparisonDirection::Above,
+            ..Settings::default()
+        };
+        let check = |output: &'static str| {
+            let status = Arc::new(StatusCache::new(move || async move { Ok(output.to_string()) }));
+            let settings = settings.clone();
+            async move { can_take_more_traffic(&settings, &status).await }
+        };
+
+        assert!(check(include_str!("../samples/passenger-5-busy.txt")).await.healthy);
+        let idle = check(include_str!("../samples/passenger-6-idle.txt")).await;
+        assert_eq!((idle.healthy, idle.reason), (false, Reason::AtCapacity));
+    }
+
+    async fn app_answering(status: u16) -> String {
+        use warp::Filter;
+        let status = warp::http::StatusCode::from_u16(status).unwrap();
+        let route = warp::path("up").map(move || warp::reply::with_status("", status));
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        format!("http://{}/up", addr)
+    }
+
+    #[tokio::test]
+    async fn readiness_needs_the_app_to_answer_too() {
+        let status = Arc::new(StatusCache::new(|| async { Ok(include_str!("../samples/passenger-6-idle.txt").to_string()) }));
+        let ready = |url: String| Settings { app_health_url: Some(url), ..Settings::default() };
+
+        let decision = check_readiness(&ready(app_answering(200).await), &status, &Warmup::new(&Settings::default())).await;
+        assert!(decision.healthy);
+
+        let decision = check_readiness(&ready(app_answering(500).await), &status, &Warmup::new(&Settings::default())).await;
+        assert!(!decision.healthy);
+        assert_eq!(decision.reason, Reason::AppUnhealthy);
+        assert_eq!(decision.error.as_deref(), Some("status 500 Internal Server Error"));
+    }
+
+    #[tokio::test]
+    async fn readiness_fails_while_the_process_count_keeps_changing() {
+        let reads = Arc::new(AtomicUsize::new(0));
+        let counted = reads.clone();
+        // 2, 3, 2, 3 processes, then steady at 3
+        let status = Arc::new(StatusCache::new(move || {
+            let processes = match counted.fetch_add(1, Ordering::SeqCst) {
+                read if read < 4 => 2 + read % 2,
+                _ => 3,
+            };
+            async move { Ok(format!("Processes : {}\nRequests in top-level queue : 0\n", processes)) }
+        }));
+        let settings = Settings { max_process_churn: Some(2), stale_after_ms: 0, ..Settings::default() };
+        let warmup = Warmup::new(&Settings::default());
+
+        for _ in 0..3 {
+            assert!(check_readiness(&settings, &status, &warmup).await.healthy);
+        }
+        let decision = check_readiness(&settings, &status, &warmup).await;
+        assert_eq!((decision.healthy, decision.reason), (false, Reason::Unstable));
+        assert_eq!(status.process_churn(&settings), 3);
+
+        // Without max_process_churn the churn is still counted, but doesn't fail readiness
+        let unchecked = Settings { max_process_churn: None, ..settings };
+        assert!(check_readiness(&unchecked, &status, &warmup).await.healthy);
+    }
+
+    #[tokio::test]
+    async fn warming_up_decides_like_health() {
+        // A short queue, but every process is running
+        let status = pool_of(6, 12);
+        let settings = Settings { healthy_streak_required: 2, full_pool_queue_threshold: Some(5), ..Settings::default() };
+        let warmup = Warmup::new(&settings);
+
+        for _ in 0..3 {
+            let decision = check_readiness(&settings, &status, &warmup).await;
+            assert_eq!((decision.healthy, decision.reason), (false, Reason::PoolExhausted));
+        }
+        assert!(!warmup.is_done());
+
+        let settings = Settings { full_pool_queue_threshold: None, ..settings };
+        assert_eq!(check_readiness(&settings, &status, &warmup).await.reason, Reason::WarmingUp);
+        assert!(check_readiness(&settings, &status, &warmup).await.healthy);
+        assert!(warmup.is_done());
+    }
+
+    #[tokio::test]
+    async fn forced_decisions_skip_passenger_status() {
+        let status = Arc::new(StatusCache::new(|| async { panic!("passenger-status was run") }));
+
+        let settings = Settings { force_healthy: true, ..Settings::default() };
+        let decision = can_take_more_traffic(&settings, &status).await;
+        assert!(decision.healthy);
+        assert_eq!(decision.reason, Reason::Forced);
+
+        let settings = Settings { force_healthy: true, force_unhealthy: true, ..Settings::default() };
+        assert!(!can_take_more_traffic(&settings, &status).await.healthy);
+    }
+
+    #[test]
+    fn old_output_lowers_the_threshold_until_everything_is_shed() {
+        let max_staleness = Duration::from_secs(10);
+        assert_eq!(penalty_for_age(Duration::ZERO, max_staleness), 0.0);
+        assert_eq!(penalty_for_age(Duration::from_secs(5), max_staleness), 0.5);
+        assert_eq!(penalty_for_age(Duration::from_secs(30), max_staleness), 1.0);
+
+        let settings = Settings::default();
+        assert!(matches!(penalized(&settings, 0.0), Cow::Borrowed(_)));
+        // Half the default 80 requests, then none at all
+        assert!(queue_has_room(39, &penalized(&settings, 0.5)));
+        assert!(!queue_has_room(40, &penalized(&settings, 0.5)));
+        assert!(!queue_has_room(0, &penalized(&settings, 1.0)));
+    }
+
+    #[tokio::test]
+    async fn stale_penalty_needs_max_staleness_and_a_read() {
+        let status = Arc::new(StatusCache::new(|| async { Ok("Requests in top-level queue : 0\n".to_string()) }));
+        let settings = Settings { max_staleness_secs: Some(60), ..Settings::default() };
+        assert_eq!(stale_penalty(&settings, &status), 0.0);
+
+        status.read(&settings).await.unwrap();
+        let penalty = stale_penalty(&settings, &status);
+        assert!((0.0..0.01).contains(&penalty), "{}", penalty);
+        assert_eq!(stale_penalty(&Settings::default(), &status), 0.0);
+    }
+
+    #[test]
+    fn reads_the_queue_with_the_configured_patterns() {
+        let output = "Version : 7.0.0\nQueued requests : 7\n";
+        assert!(queue_length(output, &Settings::default()).is_err());
+
+        let settings = Settings { queue_patterns: vec!["Waiting requests".to_string(), "Queued requests".to_string()], ..Settings::default() };
+        assert_eq!(queue_length(output, &settings).unwrap(), 7);
+    }
+
+    #[test]
+    fn queue_has_room_below_the_threshold_by_default() {
+        let settings = Settings::default();
+        assert!(queue_has_room(79, &settings));
+        assert!(!queue_has_room(80, &settings));
+    }
+
+    #[test]
+    fn combined_instances_need_every_one_healthy() {
+        let busy = Decision::new(Some(90), false, Reason::QueueFull);
+        let idle = Decision::new(Some(2), true, Reason::Ok);
+        let quiet = Decision::new(Some(5), true, Reason::Ok);
+        let down = Decision::unmeasured(&QueueError::Timeout, false);
+
+        assert_eq!(combine(vec![idle.clone(), busy.clone()], InstancePolicy::All).reason, Reason::QueueFull);
+        assert_eq!(combine(vec![idle.clone(), quiet.clone()], InstancePolicy::All).queue_length, Some(5));
+        assert_eq!(combine(vec![idle.clone(), down.clone()], InstancePolicy::All).reason, Reason::PassengerDown);
+        assert_eq!(combine(vec![busy, idle.clone()], InstancePolicy::Worst).reason, Reason::QueueFull);
+    }
+
+    #[test]
+    fn worst_policy_leaves_out_unmeasured_instances() {
+        let idle = Decision::new(Some(2), true, Reason::Ok);
+        let down = Decision::unmeasured(&QueueError::Timeout, false);
+
+        assert!(combine(vec![down.clone(), idle], InstancePolicy::Worst).healthy);
+        assert_eq!(combine(vec![down.clone(), down], InstancePolicy::Worst).reason, Reason::PassengerDown);
+    }
+
+    #[tokio::test]
+    async fn checks_every_instance() {
+        let idle = Arc::new(StatusCache::new(|| async { Ok("Requests in top-level queue : 1".to_string()) }));
+        let busy = Arc::new(StatusCache::new(|| async { Ok("Requests in top-level queue : 95".to_string()) }));
+
+        let decision = can_all_take_more_traffic(&Settings::default(), [&idle, &busy]).await;
+        assert_eq!(decision.queue_length, Some(95));
+        assert!(!decision.healthy);
+    }
+
+    #[test]
+    fn ramps_the_threshold_up_after_startup() {
+        let ramp = Duration::from_secs(100);
+        let below = ComparisonDirection::Below;
+        assert_eq!(ramped(0.5, ramp, Duration::ZERO, below), 0.05);
+        assert_eq!(ramped(0.5, ramp, Duration::from_secs(50), below), 0.25);
+        assert_eq!(ramped(0.5, ramp, Duration::from_secs(100), below), 0.5);
+        assert_eq!(ramped(0.5, ramp, Duration::from_secs(500), below), 0.5);
+        assert_eq!(ramped(0.5, Duration::ZERO, Duration::ZERO, below), 0.5);
+
+        let settings = Settings { ramp_duration_secs: 3600, ..Settings::default() };
+        assert!(effective_threshold_ratio(&settings) < 0.1);
+        assert!(!queue_has_room(12, &settings));
+        assert!(queue_has_room(12, &Settings::default()));
+    }
+
+    #[test]
+    fn ramps_an_above_threshold_down_after_startup() {
+        let ramp = Duration::from_secs(100);
+        let above = ComparisonDirection::Above;
+        assert_eq!(ramped(0.5, ramp, Duration::ZERO, above), 5.0);
+        assert_eq!(ramped(0.5, ramp, Duration::from_secs(50), above), 1.0);
+        assert_eq!(ramped(0.5, ramp, Duration::from_secs(500), above), 0.5);
+
+        // Higher is healthier, so a freshly started host needs more headroom, not less
+        let settings = Settings { comparison_direction: above, ..Settings::default() };
+        assert!(queue_has_room(90, &settings));
+        assert!(!queue_has_room(90, &Settings { ramp_duration_secs: 3600, ..settings }));
+    }
+
+    #[tokio::test]
+    async fn ramps_capacity_metrics_strictly_for_either_direction() {
+        // Every one of the six processes is busy
+        let busy = include_str!("../samples/passenger-5-busy.txt");
+        let status = Arc::new(StatusCache::new(move || async move { Ok(busy.to_string()) }));
+        let settings = Settings {
+            capacity_metric: CapacityMetric::Inflight,
+            comparison_direction: ComparisonDirection::Above,
+            ..Settings::default()
+        };
+        assert!(can_take_more_traffic(&settings, &status).await.healthy);
+
+        let ramping = Settings { ramp_duration_secs: 3600, ..settings };
+        let decision = can_take_more_traffic(&ramping, &status).await;
+        assert_eq!((decision.healthy, decision.reason), (false, Reason::AtCapacity));
+    }
+
+    #[test]
+    fn capacity_headroom_follows_the_comparison_direction() {
+        let below = Settings::default();
+        assert_eq!(capacity_headroom(0, &below), 100);
+        assert_eq!(capacity_headroom(40, &below), 50);
+        assert_eq!(capacity_headroom(80, &below), 0);
+
+        // The threshold is 80, and higher is healthier
+        let above = Settings { comparison_direction: ComparisonDirection::Above, ..Settings::default() };
+        assert_eq!(capacity_headroom(0, &above), 0);
+        assert_eq!(capacity_headroom(80, &above), 0);
+        assert_eq!(capacity_headroom(120, &above), 50);
+        assert_eq!(capacity_headroom(200, &above), 100);
+    }
+
+    #[test]
+    fn threshold_follows_queue_threshold_ratio() {
+        let settings = Settings { queue_threshold_ratio: 0.5, ..Settings::default() };
+        assert!(queue_has_room(49, &settings));
+        assert!(!queue_has_room(50, &settings));
+    }
+
+    #[test]
+    fn queue_has_room_above_the_threshold_when_inverted() {
+        let settings = Settings { comparison_direction: ComparisonDirection::Above, ..Settings::default() };
+        assert!(queue_has_room(81, &settings));
+        assert!(!queue_has_room(80, &settings));
+        assert!(!queue_has_room(0, &settings));
+    }
+
+    #[test]
+    fn passenger_not_running_follows_no_instances() {
+        let decide = |no_instances| {
+            let settings = Settings { no_instances, ..Settings::default() };
+            Decision::from_queue(Err(QueueError::NotRunning), &settings)
+        };
+
+        let decision = decide(NoInstances::Unhealthy);
+        assert!(!decision.healthy);
+        assert_eq!(decision.reason, Reason::PassengerDown);
+
+        let decision = decide(NoInstances::Healthy);
+        assert!(decision.healthy);
+        assert_eq!(decision.reason, Reason::NotRunning);
+
+        let decision = decide(NoInstances::Stopped);
+        assert!(!decision.healthy);
+        assert_eq!(decision.reason, Reason::NotRunning);
+    }
+
+    /// Reports each of `processes` in turn, then the last one from then on.
+    fn status_with_processes(processes: Vec<i32>) -> Arc<StatusCache> {
+        let reads = Arc::new(AtomicUsize::new(0));
+        Arc::new(StatusCache::new(move || {
+            let processes = processes[reads.fetch_add(1, Ordering::SeqCst).min(processes.len() - 1)];
+            async move { Ok(format!("Processes : {}\nRequests in top-level queue : 0\n", processes)) }
+        }))
+    }
+
+    #[tokio::test]
+    async fn warmup_waits_for_min_processes_and_then_stays_ready() {
+        let settings = Settings { min_ready_processes: 3, stale_after_ms: 0, ..Settings::default() };
+        let status = status_with_processes(vec![1, 3, 1]);
+        let warmup = Warmup::new(&settings);
+
+        assert_eq!(check_readiness(&settings, &status, &warmup).await.reason, Reason::WarmingUp);
+        assert!(check_readiness(&settings, &status, &warmup).await.healthy);
+        // A later dip below the minimum doesn't send it back to warming up
+        assert!(check_readiness(&settings, &status, &warmup).await.healthy);
+    }
+
+    #[tokio::test]
+    async fn warmup_waits_for_a_streak_of_passing_checks() {
+        let settings = Settings { min_ready_processes: 3, healthy_streak_required: 3, stale_after_ms: 0, ..Settings::default() };
+        // The dip to 2 processes starts the streak over
+        let status = status_with_processes(vec![3, 3, 2, 3, 3, 3, 1]);
+        let warmup = Warmup::new(&settings);
+
+        for _ in 0..5 {
+            assert_eq!(check_readiness(&settings, &status, &warmup).await.reason, Reason::WarmingUp);
+        }
+        assert!(check_readiness(&settings, &status, &warmup).await.healthy);
+        assert!(check_readiness(&settings, &status, &warmup).await.healthy);
+
+        assert!(Warmup::new(&Settings::default()).is_done());
+        assert!(!Warmup::new(&Settings { healthy_streak_required: 2, ..Settings::default() }).is_done());
+    }
+}
@@ -0,0 +1,218 @@
+use crate::check::{can_all_take_more_traffic, Decision, Reason};
+use crate::passenger::{parse_queue_length, StatusCache, STATUS_TIMEOUT};
+use crate::settings::{load_settings_with, Overrides, Settings};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Sample passenger-status outputs checked by `--self-test`, with the queue length each should parse to.
+const SELF_TEST_SAMPLES: &[(&str, &str, i32)] = &[
+    ("passenger 6 idle", include_str!("../samples/passenger-6-idle.txt"), 0),
+    ("passenger 5 busy", include_str!("../samples/passenger-5-busy.txt"), 12),
+    ("passenger 6 multiple apps", include_str!("../samples/passenger-6-multi-app.txt"), 3),
+];
+
+// Kept well under Docker's default 30s healthcheck timeout so we always get to print a result.
+const DOCKER_HEALTHCHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Pulls `--set key=value` pairs out of the command line, returning them and the remaining arguments.
+pub fn split_overrides(args: impl IntoIterator<Item = String>) -> Result<(Overrides, Vec<String>), String> {
+    let mut overrides = Vec::new();
+    let mut rest = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg != "--set" {
+            rest.push(arg);
+            continue;
+        }
+        let pair = args.next().ok_or("--set needs a key=value argument")?;
+        match pair.split_once('=') {
+            Some((key, value)) if !key.is_empty() => overrides.push((key.to_string(), value.to_string())),
+            _ => return Err(format!("--set expects key=value, got '{}'", pair)),
+        }
+    }
+    Ok((overrides, rest))
+}
+
+/// Runs the parser against the embedded samples, or against stdin when `source` is `-`.
+/// Returns the process exit code: 0 if everything parsed as expected, 1 otherwise.
+pub fn self_test(source: Option<&str>) -> i32 {
+    if source == Some("-") {
+        let mut input = String::new();
+        if let Err(e) = std::io::Read::read_to_string(&mut std::io::stdin(), &mut input) {
+            eprintln!("FAIL  stdin: {}", e);
+            return 1;
+        }
+        return match parse_queue_length(&input) {
+            Ok(queue_length) => {
+                println!("ok    stdin: queue length {}", queue_length);
+                0
+            }
+            Err(e) => {
+                println!("FAIL  stdin: {}", e);
+                1
+            }
+        };
+    }
+
+    let mut failures = 0;
+    for (name, output, expected) in SELF_TEST_SAMPLES {
+        match parse_queue_length(output) {
+            Ok(queue_length) if queue_length == *expected => {
+                println!("ok    {}: queue length {}", name, queue_length);
+            }
+            Ok(queue_length) => {
+                println!("FAIL  {}: expected {}, got {}", name, expected, queue_length);
+                failures += 1;
+            }
+            Err(e) => {
+                println!("FAIL  {}: {}", name, e);
+                failures += 1;
+            }
+        }
+    }
+
+    if failures == 0 {
+        0
+    } else {
+        1
+    }
+}
+
+/// Runs one check for `--consul-check`, prints its result and returns the exit code.
+pub async fn consul_check(settings: &Settings) -> i32 {
+    let (code, message) = consul_status(&check_once(settings, STATUS_TIMEOUT).await, settings);
+    println!("{}", message);
+    code
+}
+
+/// Runs one check for `--docker-healthcheck`, prints its result and returns the exit code.
+/// Expects no logger to be set up, so the result is the only output.
+pub async fn docker_healthcheck(overrides: &[(String, String)]) -> i32 {
+    let settings = match load_settings_with(overrides) {
+        Ok(settings) => settings,
+        Err(e) => {
+            println!("unhealthy: configuration error: {}", e);
+            return 1;
+        }
+    };
+    let (code, message) = docker_status(&check_once(&settings, DOCKER_HEALTHCHECK_TIMEOUT).await);
+    println!("{}", message);
+    code
+}
+
+/// The decision `/health` would make, for the check modes, giving passenger-status `timeout`.
+async fn check_once(settings: &Settings, timeout: Duration) -> Decision {
+    let statuses: Vec<Arc<StatusCache>> =
+        StatusCache::per_command_within(settings, timeout).into_iter().map(|(_, status)| Arc::new(status)).collect();
+    can_all_take_more_traffic(settings, &statuses).await
+}
+
+/// Maps a decision to a Consul script check exit code and output line:
+/// 0 = passing, 1 = warning, 2 = critical. A healthy queue at `queue_warn_ratio` of
+/// `max_queue_length` or more is a warning, so Consul sees it getting close before it's full. So is
+/// a failure to measure the queue when `fail_open` is set, matching `/health` staying up in that case.
+fn consul_status(decision: &Decision, settings: &Settings) -> (i32, String) {
+    let near_full = decision
+        .queue_length
+        .is_some_and(|queue_length| f64::from(queue_length) >= f64::from(settings.max_queue_length) * settings.queue_warn_ratio);
+    match (decision.healthy, decision.reason) {
+        (true, Reason::PassengerDown) => (1, format!("warning: {}", decision.describe())),
+        (true, _) if near_full => (1, format!("warning: {}, but the queue is past queue_warn_ratio", decision.describe())),
+        (true, _) => (0, format!("passing: {}", decision.describe())),
+        (false, _) => (2, format!("critical: {}", decision.describe())),
+    }
+}
+
+/// Maps a decision to a Docker `HEALTHCHECK` exit code (0 = healthy, 1 = unhealthy) and output line.
+fn docker_status(decision: &Decision) -> (i32, String) {
+    match (decision.healthy, decision.reason) {
+        (true, Reason::Ok) => (0, "healthy".to_string()),
+        (true, _) => (0, format!("healthy: {}", decision.describe())),
+        (false, _) => (1, format!("unhealthy: {}", decision.describe())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::QueueError;
+
+    #[test]
+    fn self_test_passes_on_embedded_samples() {
+        assert_eq!(self_test(None), 0);
+    }
+
+    #[test]
+    fn split_overrides_pulls_out_set_pairs() {
+        let args = ["--set", "max_queue_length=50", "--consul-check", "--set", "instance_label=a=b"];
+        let (overrides, rest) = split_overrides(args.iter().map(|arg| arg.to_string())).unwrap();
+        assert_eq!(
+            overrides,
+            vec![
+                ("max_queue_length".to_string(), "50".to_string()),
+                ("instance_label".to_string(), "a=b".to_string()),
+            ]
+        );
+        assert_eq!(rest, vec!["--consul-check".to_string()]);
+    }
+
+    #[test]
+    fn split_overrides_rejects_malformed_pairs() {
+        assert!(split_overrides(vec!["--set".to_string()]).is_err());
+        assert!(split_overrides(vec!["--set".to_string(), "max_queue_length".to_string()]).is_err());
+    }
+
+    #[test]
+    fn consul_status_follows_consul_exit_codes() {
+        let closed = Settings::default();
+        let open = Settings { fail_open: true, ..Settings::default() };
+
+        assert_eq!(consul_status(&Decision::from_queue(Ok(0), &closed), &closed).0, 0);
+        assert_eq!(consul_status(&Decision::from_queue(Ok(100), &open), &open).0, 2);
+        assert_eq!(consul_status(&Decision::from_queue(Err(QueueError::Timeout), &closed), &closed).0, 2);
+        assert_eq!(consul_status(&Decision::from_queue(Err(QueueError::Timeout), &open), &open).0, 1);
+    }
+
+    #[test]
+    fn consul_status_warns_before_the_queue_is_full() {
+        // Passing below 60 of 100, warning from there up to the threshold at 80, critical past it
+        let settings = Settings::default();
+        let status = |queue_length| consul_status(&Decision::from_queue(Ok(queue_length), &settings), &settings);
+
+        assert_eq!(status(59).0, 0);
+        let (code, message) = status(60);
+        assert_eq!(code, 1);
+        assert!(message.starts_with("warning: "), "{}", message);
+        assert_eq!(status(79).0, 1);
+        assert_eq!(status(80).0, 2);
+    }
+
+    #[test]
+    fn docker_status_exits_zero_only_when_healthy() {
+        let closed = Settings::default();
+        let open = Settings { fail_open: true, ..Settings::default() };
+
+        assert_eq!(docker_status(&Decision::from_queue(Ok(0), &closed)).0, 0);
+        assert_eq!(docker_status(&Decision::from_queue(Ok(100), &open)).0, 1);
+        assert_eq!(docker_status(&Decision::from_queue(Err(QueueError::Timeout), &closed)).0, 1);
+        assert_eq!(docker_status(&Decision::from_queue(Err(QueueError::Timeout), &open)).0, 0);
+    }
+
+    #[tokio::test]
+    async fn docker_healthcheck_decides_like_health() {
+        let path = std::env::temp_dir().join(format!("passenger-ready-docker-{}", std::process::id()));
+        std::fs::write(&path, include_str!("../samples/passenger-5-busy.txt")).unwrap();
+        let overrides = |extra: &[(&str, &str)]| {
+            let mut overrides = vec![("passenger_status_file".to_string(), path.to_string_lossy().into_owned())];
+            overrides.extend(extra.iter().map(|(key, value)| (key.to_string(), value.to_string())));
+            overrides
+        };
+
+        // 12 queued is well under the threshold, but with every process busy
+        assert_eq!(docker_healthcheck(&overrides(&[])).await, 0);
+        assert_eq!(docker_healthcheck(&overrides(&[("full_pool_queue_threshold", "5")])).await, 1);
+        assert_eq!(docker_healthcheck(&overrides(&[("capacity_metric", "inflight")])).await, 1);
+        assert_eq!(docker_healthcheck(&overrides(&[("force_unhealthy", "true")])).await, 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
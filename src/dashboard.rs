@@ -0,0 +1,65 @@
+use crate::check::{queue_threshold, Decision};
+use crate::settings::Settings;
+use chrono::{DateTime, Utc};
+use std::time::{Duration, SystemTime};
+
+// How often the page reloads itself, in seconds.
+const REFRESH_SECS: u32 = 5;
+
+/// A small self-refreshing HTML page with the current decision, for people rather than load balancers.
+/// `age` is how long ago passenger-status was last read, if it ever was.
+pub fn render(decision: &Decision, settings: &Settings, age: Option<Duration>) -> String {
+    let queue_length = decision.queue_length.map_or("unknown".to_string(), |queue_length| queue_length.to_string());
+    let (state, colour) = if decision.healthy { ("healthy", "#2e7d32") } else { ("unhealthy", "#c62828") };
+    let last_updated = age.map_or("never".to_string(), |age| {
+        format!("{} ({}s ago)", local_time(SystemTime::now() - age, settings), age.as_secs())
+    });
+
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta http-equiv="refresh" content="{refresh}">
+<title>passenger-ready: {state}</title>
+<style>body {{ font-family: sans-serif; margin: 2em; }} td {{ padding: 0.2em 1em 0.2em 0; }}</style>
+</head>
+<body>
+<h1 style="color: {colour}">{state}</h1>
+<table>
+<tr><td>Queue length</td><td>{queue_length}</td></tr>
+<tr><td>Threshold</td><td>{threshold} of {max_queue_length}</td></tr>
+<tr><td>Reason</td><td>{reason}</td></tr>
+<tr><td>Last updated</td><td>{last_updated}</td></tr>
+</table>
+</body>
+</html>
+"#,
+        refresh = REFRESH_SECS,
+        state = state,
+        colour = colour,
+        queue_length = queue_length,
+        threshold = queue_threshold(settings),
+        max_queue_length = settings.max_queue_length,
+        reason = decision.reason.as_str(),
+        last_updated = last_updated,
+    )
+}
+
+/// `time` as a wall-clock time in the configured `timezone`, for people reading the page.
+fn local_time(time: SystemTime, settings: &Settings) -> String {
+    DateTime::<Utc>::from(time).with_timezone(&settings.timezone).format("%Y-%m-%d %H:%M:%S %Z").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn times_are_shown_in_the_configured_timezone() {
+        let settings = Settings { timezone: chrono_tz::Europe::Berlin, ..Settings::default() };
+        assert_eq!(local_time(UNIX_EPOCH, &settings), "1970-01-01 01:00:00 CET");
+        assert_eq!(local_time(UNIX_EPOCH, &Settings::default()), "1970-01-01 00:00:00 UTC");
+    }
+}
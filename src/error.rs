@@ -0,0 +1,108 @@
+use std::io;
+use thiserror::Error;
+
+/// Why the queue couldn't be measured. Handlers branch on the variant; the message is for logs
+/// and the `error` field of decisions.
+#[derive(Debug, Error)]
+pub enum QueueError {
+    #[error("passenger-status timed out")]
+    Timeout,
+    /// passenger-status isn't installed, or isn't on the `PATH`.
+    #[error("passenger-status was not found")]
+    NotFound,
+    /// passenger-status couldn't be run, or exited unsuccessfully. `code` is `None` when there's no
+    /// exit status, e.g. it was killed by a signal or never started.
+    #[error("passenger-status execution failed{}", exit_details(*.code, .stderr))]
+    CommandFailed { code: Option<i32>, stderr: String },
+    /// passenger-status printed something the parser couldn't make sense of. `raw` is the text
+    /// that failed to parse: the offending line, or the whole output when a line is missing.
+    #[error("{reason}")]
+    ParseFailed { reason: String, raw: String },
+    /// passenger-status printed something, but without a queue line, as after a Passenger upgrade
+    /// that changed the format. `sample` is the start of the output.
+    #[error("unrecognized passenger-status format")]
+    UnrecognizedFormat { sample: String },
+    #[error("Phusion Passenger doesn't seem to be running")]
+    NotRunning,
+    #[error("passenger-status output is over {limit} bytes")]
+    OutputTooLarge { limit: usize },
+    /// The request already ran passenger-status `max_total_subprocess_spawns_per_request` times.
+    #[error("passenger-status already ran {limit} times for this request")]
+    SpawnLimit { limit: u32 },
+    /// `passenger_status_file` couldn't be read.
+    #[error("couldn't read passenger_status_file {path}: {reason}")]
+    StatusFileUnreadable { path: String, reason: String },
+    /// `passenger_status_file` was last written longer ago than `max_file_age_secs`.
+    #[error("passenger_status_file is {age_secs}s old, over max_file_age_secs ({max_age_secs}s)")]
+    StaleStatusFile { age_secs: u64, max_age_secs: u64 },
+    /// Any other failure, e.g. from a custom `StatusProvider`.
+    #[error("{0}")]
+    Other(String),
+}
+
+/// Why `server::run` couldn't start serving, by which socket, so the advice on fixing it can differ.
+#[derive(Debug, Error)]
+pub enum StartError {
+    /// `server_port` couldn't be bound, or the socket systemd passed in couldn't be used.
+    #[error("{0}")]
+    Tcp(#[source] io::Error),
+    /// `unix_socket_path` couldn't be bound.
+    #[error("{0}")]
+    UnixSocket(#[source] io::Error),
+}
+
+impl QueueError {
+    /// The variant's name in snake case, e.g. `command_failed`, for telling failures apart in `/errors`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            QueueError::Timeout => "timeout",
+            QueueError::NotFound => "not_found",
+            QueueError::CommandFailed { .. } => "command_failed",
+            QueueError::ParseFailed { .. } => "parse_failed",
+            QueueError::UnrecognizedFormat { .. } => "unrecognized_format",
+            QueueError::NotRunning => "not_running",
+            QueueError::OutputTooLarge { .. } => "output_too_large",
+            QueueError::SpawnLimit { .. } => "spawn_limit",
+            QueueError::StatusFileUnreadable { .. } => "status_file_unreadable",
+            QueueError::StaleStatusFile { .. } => "stale_status_file",
+            QueueError::Other(_) => "other",
+        }
+    }
+}
+
+impl From<anyhow::Error> for QueueError {
+    /// Recovers a `QueueError` that went through a `StatusProvider`, keeping anything else as `Other`.
+    fn from(error: anyhow::Error) -> QueueError {
+        match error.downcast::<QueueError>() {
+            Ok(error) => error,
+            Err(error) => QueueError::Other(error.to_string()),
+        }
+    }
+}
+
+fn exit_details(code: Option<i32>, stderr: &str) -> String {
+    let stderr = stderr.trim();
+    match (code, stderr.is_empty()) {
+        (Some(code), true) => format!(" (exit status {})", code),
+        (Some(code), false) => format!(" (exit status {}): {}", code, stderr),
+        (None, true) => String::new(),
+        (None, false) => format!(": {}", stderr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn provider_errors_keep_their_variant() {
+        assert!(matches!(QueueError::from(anyhow::Error::from(QueueError::Timeout)), QueueError::Timeout));
+        assert!(matches!(QueueError::from(anyhow::anyhow!("custom")), QueueError::Other(message) if message == "custom"));
+    }
+
+    #[test]
+    fn command_failures_include_exit_status_and_stderr() {
+        let error = QueueError::CommandFailed { code: Some(1), stderr: "permission denied\n".to_string() };
+        assert_eq!(error.to_string(), "passenger-status execution failed (exit status 1): permission denied");
+    }
+}
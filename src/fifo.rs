@@ -0,0 +1,102 @@
+use crate::check::Decision;
+use crate::settings::Settings;
+use log::{debug, warn};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Writes a JSON line to `status_fifo` whenever `/health` flips between healthy and unhealthy,
+/// including the first check. Lines are dropped while nothing is reading the FIFO.
+#[derive(Default)]
+pub struct StateChanges {
+    last_healthy: Mutex<Option<bool>>,
+}
+
+impl StateChanges {
+    pub fn new() -> StateChanges {
+        StateChanges::default()
+    }
+
+    pub fn observe(&self, settings: &Settings, decision: &Decision) {
+        let path = match settings.status_fifo.as_deref() {
+            Some(path) if !path.is_empty() => Path::new(path),
+            _ => return,
+        };
+        let mut last_healthy = self.last_healthy.lock().unwrap();
+        if *last_healthy == Some(decision.healthy) {
+            return;
+        }
+        *last_healthy = Some(decision.healthy);
+
+        if let Err(e) = write_line(path, decision) {
+            // ENXIO (no reader) and EAGAIN (reader not keeping up) are expected, so this stays quiet
+            debug!("Dropped state change for {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn write_line(path: &Path, decision: &Decision) -> io::Result<()> {
+    if !path.metadata()?.file_type().is_fifo() {
+        warn!("status_fifo {} is not a FIFO, not writing to it", path.display());
+        return Ok(());
+    }
+    // Non-blocking, so opening fails straight away instead of waiting for a reader
+    let mut fifo = OpenOptions::new().write(true).custom_flags(libc::O_NONBLOCK).open(path)?;
+    let mut line = serde_json::to_string(decision)?;
+    line.push('\n');
+    fifo.write_all(line.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::check::Reason;
+    use std::ffi::CString;
+    use std::io::Read;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::PathBuf;
+
+    fn fifo(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("passenger-ready-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+        path
+    }
+
+    fn writing_to(path: &Path) -> Settings {
+        Settings { status_fifo: Some(path.to_str().unwrap().to_string()), ..Settings::default() }
+    }
+
+    #[test]
+    fn writes_a_line_per_state_change() {
+        let path = fifo("changes");
+        let mut reader = OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(&path).unwrap();
+        let changes = StateChanges::new();
+        let settings = writing_to(&path);
+
+        changes.observe(&settings, &Decision::new(Some(0), true, Reason::Ok));
+        changes.observe(&settings, &Decision::new(Some(1), true, Reason::Ok));
+        changes.observe(&settings, &Decision::new(Some(90), false, Reason::QueueFull));
+
+        let mut output = String::new();
+        reader.read_to_string(&mut output).ok();
+        let lines: Vec<serde_json::Value> = output.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["healthy"], true);
+        assert_eq!(lines[1]["reason"], "queue_full");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn drops_state_changes_without_a_reader() {
+        let path = fifo("no-reader");
+        let changes = StateChanges::new();
+
+        // Would block forever if the FIFO were opened without O_NONBLOCK
+        changes.observe(&writing_to(&path), &Decision::new(Some(0), true, Reason::Ok));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,32 @@
+//! Reports whether Phusion Passenger's request queue has room for more traffic.
+//!
+//! The `passenger-ready` binary serves these checks over HTTP. The modules here let another
+//! Rust service run the same checks in-process, or mount the routes into its own warp server.
+
+pub mod access_log;
+pub mod allowlist;
+pub mod check;
+pub mod cli;
+#[cfg(feature = "full")]
+pub mod dashboard;
+pub mod error;
+#[cfg(feature = "full")]
+pub mod fifo;
+mod listener;
+#[cfg(feature = "full")]
+pub mod metrics;
+pub mod passenger;
+pub mod pool_json;
+pub mod remote_config;
+pub mod server;
+pub mod settings;
+#[cfg(feature = "full")]
+pub mod statsd;
+pub mod template;
+#[cfg(test)]
+mod testing;
+
+pub use check::{can_take_more_traffic, get_queue_length, Decision, Reason};
+pub use error::QueueError;
+pub use passenger::{parse_queue_length, PassengerStatus, StatusCache, StatusProvider};
+pub use settings::{load_settings, load_settings_with, Settings};
@@ -0,0 +1,258 @@
+use futures_util::stream::{self, Stream};
+use log::warn;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// The first file descriptor systemd passes to socket-activated services.
+const SD_LISTEN_FDS_START: RawFd = 3;
+/// How long to wait after a failed accept, like running out of file descriptors, before trying again.
+/// Retrying straight away would spin while the condition lasts.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(50);
+
+/// The listening socket systemd passed in, if the process was socket-activated. `LISTEN_PID` has to
+/// name this process, so a child that inherited the variables doesn't take the socket too.
+pub fn systemd_listener() -> io::Result<Option<TcpListener>> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+    let fd = match passed_fd(pid.as_deref(), fds.as_deref(), std::process::id()) {
+        Some(fd) => fd,
+        None => return Ok(None),
+    };
+    // Safe as long as systemd's contract holds: the descriptor is open, ours, and a socket
+    let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener).map(Some)
+}
+
+fn passed_fd(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> Option<RawFd> {
+    let for_us = listen_pid?.parse::<u32>().ok()? == pid;
+    let count = listen_fds?.parse::<u32>().ok()?;
+    (for_us && count >= 1).then_some(SD_LISTEN_FDS_START)
+}
+
+/// A socket to serve on.
+pub enum Listener {
+    Tcp(TcpListener),
+    /// With the path it's bound to, for removing once the server stops.
+    Unix(UnixListener, PathBuf),
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Listener {
+        Listener::Tcp(listener)
+    }
+}
+
+impl Listener {
+    /// Binds a Unix socket at `path`. A socket file left behind by a process that's gone is
+    /// replaced, but one something still listens on is an error, like a port in use.
+    pub fn bind_unix(path: &str) -> io::Result<Listener> {
+        let stale = std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket());
+        if stale && std::os::unix::net::UnixStream::connect(path).is_err() {
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path).map_err(|e| io::Error::new(e.kind(), format!("couldn't bind unix_socket_path {}: {}", path, e)))?;
+        Ok(Listener::Unix(listener, PathBuf::from(path)))
+    }
+
+    /// The socket file to remove once the server stops, for a Unix socket.
+    pub fn socket_path(&self) -> Option<&PathBuf> {
+        match self {
+            Listener::Tcp(_) => None,
+            Listener::Unix(_, path) => Some(path),
+        }
+    }
+}
+
+/// Something connections are accepted from, so tests can stand in for a listener.
+trait Accept {
+    fn accept(&self) -> impl Future<Output = io::Result<Connection>> + Send;
+}
+
+impl Accept for Listener {
+    async fn accept(&self) -> io::Result<Connection> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().await.map(|(stream, _)| Connection::Tcp(stream)),
+            Listener::Unix(listener, _) => listener.accept().await.map(|(stream, _)| Connection::Unix(stream)),
+        }
+    }
+}
+
+/// Accepted connections from all of `listeners`, at most `max_connections` open at a time between
+/// them. Connections past the limit are closed as soon as they're accepted, so a flood of them
+/// can't exhaust file descriptors. Failed accepts are logged and retried rather than passed on,
+/// since hyper stops serving on the first one.
+pub fn limit_connections(listeners: Vec<Listener>, max_connections: usize) -> impl Stream<Item = io::Result<LimitedConnection>> {
+    let permits = Arc::new(Semaphore::new(max_connections));
+    stream::select_all(listeners.into_iter().map(|listener| Box::pin(limit_listener(listener, permits.clone(), max_connections))))
+}
+
+fn limit_listener(
+    listener: impl Accept,
+    permits: Arc<Semaphore>,
+    max_connections: usize,
+) -> impl Stream<Item = io::Result<LimitedConnection>> {
+    stream::unfold((listener, permits), move |(listener, permits)| async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Could not accept a connection, retrying: {}", e);
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            };
+            match permits.clone().try_acquire_owned() {
+                Ok(permit) => {
+                    let connection = LimitedConnection { stream, _permit: permit };
+                    return Some((Ok(connection), (listener, permits)));
+                }
+                // Dropping the stream closes the connection
+                Err(_) => warn!("Reached max_connections ({}), closing new connection", max_connections),
+            }
+        }
+    })
+}
+
+enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+/// A connection holding one of the `max_connections` permits until it's closed.
+pub struct LimitedConnection {
+    stream: Connection,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl LimitedConnection {
+    /// The client's address. Unix socket clients don't have one.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match &self.stream {
+            Connection::Tcp(stream) => stream.peer_addr().ok(),
+            Connection::Unix(_) => None,
+        }
+    }
+}
+
+impl AsyncRead for LimitedConnection {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match &mut self.stream {
+            Connection::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for LimitedConnection {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match &mut self.stream {
+            Connection::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.stream {
+            Connection::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.stream {
+            Connection::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Connection::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn uses_the_socket_systemd_passed_to_this_process() {
+        assert_eq!(passed_fd(Some("42"), Some("1"), 42), Some(3));
+        assert_eq!(passed_fd(Some("41"), Some("1"), 42), None);
+        assert_eq!(passed_fd(Some("42"), Some("0"), 42), None);
+        assert_eq!(passed_fd(None, None, 42), None);
+    }
+
+    #[tokio::test]
+    async fn closes_connections_past_the_limit() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut connections = Box::pin(limit_connections(vec![listener.into()], 1));
+
+        let _first = TcpStream::connect(addr).await.unwrap();
+        let held = connections.next().await.unwrap().unwrap();
+
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let accepting = tokio::spawn(async move { connections.next().await.map(|connection| connection.is_ok()) });
+
+        // The second connection is closed straight away, without being handed out
+        assert_eq!(second.read(&mut [0; 1]).await.unwrap(), 0);
+
+        // Once the first one closes, there's room again
+        drop(held);
+        let _third = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(accepting.await.unwrap(), Some(true));
+    }
+
+    /// A listener whose first `failures` accepts fail, as they do when file descriptors run out.
+    struct Failing {
+        listener: Listener,
+        failures: std::sync::atomic::AtomicUsize,
+    }
+
+    impl Accept for Failing {
+        async fn accept(&self) -> io::Result<Connection> {
+            let left = self.failures.load(std::sync::atomic::Ordering::SeqCst);
+            if left > 0 {
+                self.failures.store(left - 1, std::sync::atomic::Ordering::SeqCst);
+                return Err(io::Error::from_raw_os_error(24));
+            }
+            self.listener.accept().await
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_accepting_after_accept_errors() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let failing = Failing { listener: listener.into(), failures: 3.into() };
+        let mut connections = Box::pin(limit_listener(failing, Arc::new(Semaphore::new(1)), 1));
+
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let connection = connections.next().await.unwrap();
+        assert!(connection.unwrap().peer_addr().is_some());
+    }
+
+    #[tokio::test]
+    async fn replaces_stale_unix_sockets_but_not_ones_in_use() {
+        let path = std::env::temp_dir().join(format!("passenger-ready-{}-stale.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+        drop(std::os::unix::net::UnixListener::bind(path).unwrap());
+
+        let listener = Listener::bind_unix(path).unwrap();
+        assert_eq!(listener.socket_path().map(|path| path.to_str().unwrap()), Some(path));
+        assert_eq!(Listener::bind_unix(path).err().map(|e| e.kind()), Some(io::ErrorKind::AddrInUse));
+
+        drop(listener);
+        std::fs::remove_file(path).unwrap();
+    }
+}
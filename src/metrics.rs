@@ -0,0 +1,479 @@
+use crate::check::{check_deadline, mark_degraded, queue_length, queue_threshold, Decision};
+use crate::error::QueueError;
+use crate::passenger::{stderr_warnings, StatusCache};
+use crate::settings::Settings;
+use log::{info, warn};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::timeout;
+
+/// One sample on `/metrics`. Samples of the same metric with different labels go one after another.
+#[derive(Debug, PartialEq)]
+pub struct Metric {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: MetricKind,
+    pub labels: Vec<(&'static str, String)>,
+    pub value: f64,
+}
+
+/// The Prometheus metric type, for the `# TYPE` line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MetricKind {
+    Gauge,
+    Counter,
+    Summary,
+}
+
+impl MetricKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MetricKind::Gauge => "gauge",
+            MetricKind::Counter => "counter",
+            MetricKind::Summary => "summary",
+        }
+    }
+}
+
+fn gauge(name: &'static str, help: &'static str, value: f64) -> Metric {
+    Metric { name, help, kind: MetricKind::Gauge, labels: Vec::new(), value }
+}
+
+/// Reads the queue and reports it along with how old the reading is. Samples carry no
+/// timestamps of their own, so Prometheus records them at scrape time; the age of the
+/// underlying passenger-status output is `passenger_queue_last_updated_seconds` instead.
+pub async fn collect(settings: &Settings, status: &Arc<StatusCache>) -> Vec<Metric> {
+    let output = match timeout(check_deadline(settings), status.read(settings)).await {
+        Ok(Ok(output)) => Some(output),
+        _ => None,
+    };
+    let parsed = output.as_deref().map(|output| queue_length(output, settings));
+    let unrecognized = matches!(parsed, Some(Err(QueueError::UnrecognizedFormat { .. })));
+    let queue_length = parsed.and_then(Result::ok);
+    let age = status.last_fetched().map(|fetched_at| fetched_at.elapsed());
+
+    let mut metrics = Vec::new();
+    if let Some(queue_length) = queue_length {
+        metrics.push(gauge("passenger_queue_length", "Requests in Passenger's top-level queue.", queue_length as f64));
+    }
+    if let Some(age) = age {
+        metrics.push(gauge(
+            "passenger_queue_last_updated_seconds",
+            "Unix time passenger-status was last read successfully.",
+            unix_time(age),
+        ));
+    }
+    // Never having read passenger-status counts as stale too
+    let stale = queue_length.is_none() || age.is_none_or(|age| age > Duration::from_millis(settings.max_queue_age_ms));
+    metrics.push(gauge(
+        "passenger_queue_stale",
+        "Whether the queue length is missing or older than max_queue_age_ms.",
+        if stale { 1.0 } else { 0.0 },
+    ));
+    metrics.push(gauge(
+        "passenger_status_format_unrecognized",
+        "Whether passenger-status output has no queue line, as after an upgrade that changed its format.",
+        if unrecognized { 1.0 } else { 0.0 },
+    ));
+    if settings.degraded_memory_mb.is_some() || settings.degraded_queue_ratio.is_some() {
+        let degraded = match (&output, queue_length) {
+            (Some(output), Some(queue_length)) => mark_degraded(Decision::from_queue(Ok(queue_length), settings), output, settings).degraded,
+            _ => false,
+        };
+        metrics.push(gauge(
+            "passenger_degraded",
+            "Whether the host is healthy but past degraded_memory_mb or degraded_queue_ratio.",
+            if degraded { 1.0 } else { 0.0 },
+        ));
+    }
+    if settings.warn_on_status_stderr {
+        metrics.push(Metric {
+            name: "passenger_status_warnings",
+            help: "Successful passenger-status runs that printed to stderr.",
+            kind: MetricKind::Counter,
+            labels: Vec::new(),
+            value: stderr_warnings() as f64,
+        });
+    }
+    metrics
+}
+
+/// `passenger_version_info`, always 1, labelled with the Passenger `version` so it can be joined
+/// onto other metrics per host.
+pub fn passenger_version(version: &str) -> Metric {
+    Metric {
+        labels: vec![("version", version.to_string())],
+        ..gauge("passenger_version_info", "The Passenger version, from passenger --version at startup.", 1.0)
+    }
+}
+
+/// How many of the last `warn_window` health checks were in the warning band: healthy, but with the
+/// queue at `queue_warn_ratio` of `max_queue_length` or more. A host that stays there is chronically
+/// near capacity even if it never sheds traffic. Checks that couldn't measure the queue don't count.
+#[derive(Default)]
+pub struct WarnBand {
+    // Whether each recent check was in the band, oldest first, and whether the last full window
+    // was over `warn_fraction_alert`
+    checks: Mutex<(VecDeque<bool>, bool)>,
+}
+
+impl WarnBand {
+    pub fn new() -> WarnBand {
+        WarnBand::default()
+    }
+
+    /// Logs a warning once the band's share of a full window goes over `warn_fraction_alert`, and
+    /// again when it drops back.
+    pub fn observe(&self, settings: &Settings, decision: &Decision) {
+        let queue_length = match decision.queue_length {
+            Some(queue_length) => queue_length,
+            None => return,
+        };
+        let in_band = decision.healthy && f64::from(queue_length) >= f64::from(settings.max_queue_length) * settings.queue_warn_ratio;
+        let mut checks = self.checks.lock().unwrap();
+        let (window, alerting) = &mut *checks;
+        window.push_back(in_band);
+        while window.len() > settings.warn_window.max(1) {
+            window.pop_front();
+        }
+        if window.len() < settings.warn_window {
+            return;
+        }
+        let over = fraction(window) > settings.warn_fraction_alert;
+        if over && !*alerting {
+            warn!(
+                "The queue was near the threshold in {:.0}% of the last {} checks, this host may need more capacity",
+                fraction(window) * 100.0,
+                window.len()
+            );
+        } else if !over && *alerting {
+            info!("The queue is no longer near the threshold most of the time");
+        }
+        *alerting = over;
+    }
+
+    /// `passenger_queue_warn_fraction`, once there's been a check to go on.
+    pub fn metric(&self) -> Option<Metric> {
+        let checks = self.checks.lock().unwrap();
+        if checks.0.is_empty() {
+            return None;
+        }
+        Some(gauge(
+            "passenger_queue_warn_fraction",
+            "Share of the last warn_window checks that were healthy but at queue_warn_ratio or more.",
+            fraction(&checks.0),
+        ))
+    }
+}
+
+/// Where one check's queue fell, for `passenger_queue_apdex`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Satisfaction {
+    /// Below `queue_warn_ratio`.
+    Satisfied,
+    /// From `queue_warn_ratio` up to the `queue_threshold_ratio` threshold.
+    Tolerating,
+    /// At the threshold or past it.
+    Frustrated,
+}
+
+/// An Apdex score of queue depth over the last `warn_window` checks that measured the queue:
+/// `(satisfied + tolerating / 2) / total`.
+#[derive(Default)]
+pub struct Apdex {
+    checks: Mutex<VecDeque<Satisfaction>>,
+}
+
+impl Apdex {
+    pub fn new() -> Apdex {
+        Apdex::default()
+    }
+
+    pub fn observe(&self, settings: &Settings, decision: &Decision) {
+        let queue_length = match decision.queue_length {
+            Some(queue_length) => queue_length as f64,
+            None => return,
+        };
+        let satisfaction = if queue_length < f64::from(settings.max_queue_length) * settings.queue_warn_ratio {
+            Satisfaction::Satisfied
+        } else if queue_length < f64::from(queue_threshold(settings)) {
+            Satisfaction::Tolerating
+        } else {
+            Satisfaction::Frustrated
+        };
+        let mut checks = self.checks.lock().unwrap();
+        checks.push_back(satisfaction);
+        while checks.len() > settings.warn_window.max(1) {
+            checks.pop_front();
+        }
+    }
+
+    /// `passenger_queue_apdex`, once there's been a check to go on.
+    pub fn metric(&self) -> Option<Metric> {
+        let checks = self.checks.lock().unwrap();
+        if checks.is_empty() {
+            return None;
+        }
+        let count = |satisfaction| checks.iter().filter(|check| **check == satisfaction).count() as f64;
+        let score = (count(Satisfaction::Satisfied) + count(Satisfaction::Tolerating) / 2.0) / checks.len() as f64;
+        Some(gauge(
+            "passenger_queue_apdex",
+            "Apdex of queue depth over the last warn_window checks, below queue_warn_ratio satisfied and past queue_threshold_ratio frustrated.",
+            score,
+        ))
+    }
+}
+
+// Upper bounds, in seconds, of the buckets request durations are counted in. Slower requests go in
+// one more, unbounded, bucket.
+const DURATION_BUCKETS: [f64; 12] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+// The quantiles `passenger_ready_request_duration_seconds` reports
+const DURATION_QUANTILES: [(f64, &str); 2] = [(0.5, "0.5"), (0.95, "0.95")];
+
+/// How long each endpoint has taken to answer since startup, counted in `DURATION_BUCKETS`, for
+/// the p50 and p95 in `passenger_ready_request_duration_seconds`.
+#[derive(Default)]
+pub struct RequestDurations {
+    endpoints: Mutex<BTreeMap<&'static str, Histogram>>,
+}
+
+#[derive(Default)]
+struct Histogram {
+    counts: [u64; DURATION_BUCKETS.len() + 1],
+    slowest: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        let bucket = DURATION_BUCKETS.iter().position(|bound| seconds <= *bound).unwrap_or(DURATION_BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.slowest = self.slowest.max(seconds);
+    }
+
+    /// The upper bound of the bucket the `q` quantile falls in, or the slowest request when that's
+    /// sooner.
+    fn quantile(&self, q: f64) -> f64 {
+        let rank = (q * self.counts.iter().sum::<u64>() as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return DURATION_BUCKETS.get(bucket).map_or(self.slowest, |bound| bound.min(self.slowest));
+            }
+        }
+        self.slowest
+    }
+}
+
+impl RequestDurations {
+    pub fn new() -> RequestDurations {
+        RequestDurations::default()
+    }
+
+    pub fn observe(&self, endpoint: &'static str, duration: Duration) {
+        self.endpoints.lock().unwrap().entry(endpoint).or_default().observe(duration.as_secs_f64());
+    }
+
+    /// `passenger_ready_request_duration_seconds` for each endpoint that's been requested.
+    pub fn metrics(&self) -> Vec<Metric> {
+        let endpoints = self.endpoints.lock().unwrap();
+        endpoints
+            .iter()
+            .flat_map(|(endpoint, histogram)| {
+                DURATION_QUANTILES.iter().map(move |(q, quantile)| Metric {
+                    name: "passenger_ready_request_duration_seconds",
+                    help: "How long each endpoint took to answer since startup, by quantile.",
+                    kind: MetricKind::Summary,
+                    labels: vec![("endpoint", endpoint.to_string()), ("quantile", quantile.to_string())],
+                    value: histogram.quantile(*q),
+                })
+            })
+            .collect()
+    }
+}
+
+fn fraction(window: &VecDeque<bool>) -> f64 {
+    window.iter().filter(|in_band| **in_band).count() as f64 / window.len() as f64
+}
+
+/// The Prometheus text format: `# HELP` and `# TYPE` lines for each metric, then its `name{labels} value`
+/// samples.
+pub fn render(metrics: &[Metric]) -> String {
+    let mut text = String::new();
+    for (i, metric) in metrics.iter().enumerate() {
+        let name = metric.name;
+        if i == 0 || metrics[i - 1].name != name {
+            writeln!(text, "# HELP {} {}\n# TYPE {} {}", name, metric.help, name, metric.kind.as_str()).unwrap();
+        }
+        let labels: Vec<String> = metric.labels.iter().map(|(label, value)| format!("{}=\"{}\"", label, value)).collect();
+        if labels.is_empty() {
+            writeln!(text, "{} {}", name, metric.value).unwrap();
+        } else {
+            writeln!(text, "{}{{{}}} {}", name, labels.join(","), metric.value).unwrap();
+        }
+    }
+    text
+}
+
+/// Unix time, in seconds, of `age` ago.
+fn unix_time(age: Duration) -> f64 {
+    let then = SystemTime::now() - age;
+    then.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(metrics: &[Metric], name: &str) -> Option<f64> {
+        metrics.iter().find(|metric| metric.name == name).map(|metric| metric.value)
+    }
+
+    #[tokio::test]
+    async fn reports_fresh_queue_length() {
+        let status = Arc::new(StatusCache::new(|| async { Ok("Requests in top-level queue : 7".to_string()) }));
+        let metrics = collect(&Settings::default(), &status).await;
+
+        assert_eq!(value(&metrics, "passenger_queue_length"), Some(7.0));
+        assert!(value(&metrics, "passenger_queue_last_updated_seconds").unwrap() > 0.0);
+        assert_eq!(value(&metrics, "passenger_queue_stale"), Some(0.0));
+    }
+
+    #[tokio::test]
+    async fn reports_stale_when_passenger_status_fails() {
+        let status = Arc::new(StatusCache::new(|| async { Err(anyhow::anyhow!("passenger-status execution failed")) }));
+        let metrics = collect(&Settings::default(), &status).await;
+
+        assert_eq!(value(&metrics, "passenger_queue_length"), None);
+        assert_eq!(value(&metrics, "passenger_queue_last_updated_seconds"), None);
+        assert_eq!(value(&metrics, "passenger_queue_stale"), Some(1.0));
+    }
+
+    #[tokio::test]
+    async fn flags_unrecognized_passenger_status_output() {
+        let status = Arc::new(StatusCache::new(|| async { Ok("Queued requests : 7".to_string()) }));
+        let metrics = collect(&Settings::default(), &status).await;
+        assert_eq!(value(&metrics, "passenger_status_format_unrecognized"), Some(1.0));
+
+        let status = Arc::new(StatusCache::new(|| async { Ok("Requests in top-level queue : 7".to_string()) }));
+        let metrics = collect(&Settings::default(), &status).await;
+        assert_eq!(value(&metrics, "passenger_status_format_unrecognized"), Some(0.0));
+    }
+
+    #[tokio::test]
+    async fn reports_stale_when_cached_output_is_too_old() {
+        let status = Arc::new(StatusCache::new(|| async { Ok("Requests in top-level queue : 7".to_string()) }));
+        let settings = Settings { stale_after_ms: 60_000, max_queue_age_ms: 10, ..Settings::default() };
+        collect(&settings, &status).await;
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        let metrics = collect(&settings, &status).await;
+        assert_eq!(value(&metrics, "passenger_queue_length"), Some(7.0));
+        assert_eq!(value(&metrics, "passenger_queue_stale"), Some(1.0));
+    }
+
+    #[tokio::test]
+    async fn reports_degraded_only_when_configured() {
+        let status = Arc::new(StatusCache::new(|| async { Ok("Requests in top-level queue : 70".to_string()) }));
+        assert_eq!(value(&collect(&Settings::default(), &status).await, "passenger_degraded"), None);
+
+        let settings = Settings { degraded_queue_ratio: Some(0.5), ..Settings::default() };
+        assert_eq!(value(&collect(&settings, &status).await, "passenger_degraded"), Some(1.0));
+        let settings = Settings { degraded_queue_ratio: Some(0.75), ..Settings::default() };
+        assert_eq!(value(&collect(&settings, &status).await, "passenger_degraded"), Some(0.0));
+    }
+
+    #[tokio::test]
+    async fn counts_status_warnings_only_when_enabled() {
+        let status = Arc::new(StatusCache::new(|| async { Ok("Requests in top-level queue : 7".to_string()) }));
+        assert_eq!(value(&collect(&Settings::default(), &status).await, "passenger_status_warnings"), None);
+
+        let settings = Settings { warn_on_status_stderr: true, ..Settings::default() };
+        let metrics = collect(&settings, &status).await;
+        assert_eq!(metrics.iter().find(|metric| metric.name == "passenger_status_warnings").unwrap().kind, MetricKind::Counter);
+    }
+
+    #[test]
+    fn tracks_checks_in_the_warning_band() {
+        let settings = Settings { warn_window: 4, ..Settings::default() };
+        let band = WarnBand::new();
+        assert_eq!(band.metric(), None);
+
+        for queue_length in [10, 65, 70, 90] {
+            band.observe(&settings, &Decision::from_queue(Ok(queue_length), &settings));
+        }
+        band.observe(&settings, &Decision::from_queue(Err(QueueError::Timeout), &settings));
+        assert_eq!(band.metric().unwrap().value, 0.5);
+
+        band.observe(&settings, &Decision::from_queue(Ok(60), &settings));
+        assert_eq!(band.metric().unwrap().value, 0.75);
+        assert!(band.checks.lock().unwrap().1);
+    }
+
+    #[test]
+    fn scores_queue_depth_like_apdex() {
+        let settings = Settings { warn_window: 4, ..Settings::default() };
+        let apdex = Apdex::new();
+        assert_eq!(apdex.metric(), None);
+
+        // Satisfied below 60, tolerating from 60, frustrated from 80
+        for queue_length in [99, 10, 59, 60, 85] {
+            apdex.observe(&settings, &Decision::from_queue(Ok(queue_length), &settings));
+        }
+        apdex.observe(&settings, &Decision::from_queue(Err(QueueError::Timeout), &settings));
+        assert_eq!(apdex.metric().unwrap().value, 0.625);
+    }
+
+    #[test]
+    fn renders_help_and_type_before_each_sample() {
+        let metrics = [gauge("passenger_queue_length", "Requests queued.", 7.0)];
+        assert_eq!(
+            render(&metrics),
+            "# HELP passenger_queue_length Requests queued.\n# TYPE passenger_queue_length gauge\npassenger_queue_length 7\n"
+        );
+    }
+
+    #[test]
+    fn reports_request_duration_quantiles_per_endpoint() {
+        let durations = RequestDurations::new();
+        for millis in [2, 3, 4, 4, 20, 30, 40, 60, 80, 700] {
+            durations.observe("health", Duration::from_millis(millis));
+        }
+        durations.observe("status", Duration::from_secs(9));
+
+        let metrics = durations.metrics();
+        let quantiles: Vec<(&str, &str, f64)> =
+            metrics.iter().map(|metric| (metric.labels[0].1.as_str(), metric.labels[1].1.as_str(), metric.value)).collect();
+        assert_eq!(quantiles, [("health", "0.5", 0.025), ("health", "0.95", 0.7), ("status", "0.5", 9.0), ("status", "0.95", 9.0)]);
+        assert_eq!(
+            render(&durations.metrics()[..2]),
+            "# HELP passenger_ready_request_duration_seconds How long each endpoint took to answer since startup, by quantile.\n\
+             # TYPE passenger_ready_request_duration_seconds summary\n\
+             passenger_ready_request_duration_seconds{endpoint=\"health\",quantile=\"0.5\"} 0.025\n\
+             passenger_ready_request_duration_seconds{endpoint=\"health\",quantile=\"0.95\"} 0.7\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn every_sample_has_help_and_type() {
+        let status = Arc::new(StatusCache::new(|| async { Ok("Requests in top-level queue : 7".to_string()) }));
+        let output = render(&collect(&Settings::default(), &status).await);
+
+        let lines: Vec<&str> = output.lines().collect();
+        let samples: Vec<&str> = lines.iter().filter(|line| !line.starts_with('#')).copied().collect();
+        assert_eq!(samples.len(), 4);
+        for sample in samples {
+            let name = sample.split(' ').next().unwrap();
+            assert!(lines.iter().any(|line| line.starts_with(&format!("# HELP {} ", name))), "{} has no HELP", name);
+            assert!(
+                lines.contains(&format!("# TYPE {} gauge", name).as_str())
+                    || lines.contains(&format!("# TYPE {} counter", name).as_str()),
+                "{} has no TYPE",
+                name
+            );
+        }
+    }
+}
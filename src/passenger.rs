@@ -0,0 +1,1185 @@
+use crate::error::QueueError;
+use crate::pool_json;
+use crate::settings::{RetryOn, Settings, StatusFormat};
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use regex::Regex;
+use serde::Serialize;
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::process::Stdio;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::timeout;
+
+const QUEUE_LINE_PATTERN: &str = "Requests in top-level queue";
+const PROCESSES_LINE_PATTERN: &str = "Processes";
+const MAX_POOL_SIZE_LINE_PATTERN: &str = "Max pool size";
+const APP_QUEUE_LINE_PATTERN: &str = "Requests in queue";
+// What passenger-status prints instead of a status report when there's no Passenger instance.
+const NOT_RUNNING_PATTERN: &str = "doesn't seem to be running";
+// How much of unrecognized output is kept for the warning, enough to see what it looks like.
+const UNRECOGNIZED_SAMPLE_CHARS: usize = 200;
+// How much of the stderr of a successful run is logged with `warn_on_status_stderr`.
+const STDERR_WARNING_CHARS: usize = 500;
+// Likewise for the messages `/errors` keeps, which hold stderr for failed runs
+const ERROR_MESSAGE_CHARS: usize = 500;
+// What `report_passenger_version` runs, which prints e.g. "Phusion Passenger(R) 6.0.18".
+const VERSION_COMMAND: &str = "passenger --version";
+
+// Set once the unrecognized format warning is logged and cleared when output parses again, so a
+// format change is logged once rather than on every check.
+static WARNED_UNRECOGNIZED: AtomicBool = AtomicBool::new(false);
+
+// Successful runs that printed to stderr, counted with `warn_on_status_stderr`.
+static STDERR_WARNINGS: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    // The value is the last number on the line; anything before it may contain colons (e.g. timestamps).
+    // Some views print it with thousands separators ("1,234") or as a float ("0.0").
+    static ref TRAILING_NUMBER: Regex = Regex::new(r"(-?\d[\d,]*(?:\.\d+)?)\s*$").unwrap();
+    // Each process is listed as "* PID: 2231 ...", with its memory on the next line as "Memory  : 410M"
+    static ref PROCESS_PID: Regex = Regex::new(r"\*\s*PID:\s*(\d+)").unwrap();
+    static ref PROCESS_SESSIONS: Regex = Regex::new(r"\*\s*PID:\s*\d+\s+Sessions\s*:\s*(\d+)").unwrap();
+    static ref VERSION: Regex = Regex::new(r"\b(\d+\.\d+(?:\.\d+)*)\b").unwrap();
+    static ref PROCESS_MEMORY: Regex = Regex::new(r"Memory\s*:\s*(\d+(?:\.\d+)?)([KMG])").unwrap();
+}
+
+/// How many times the current request may still run passenger-status, when it's limited.
+struct SpawnBudget {
+    limit: u32,
+    left: Cell<u32>,
+}
+
+tokio::task_local! {
+    static SPAWN_BUDGET: SpawnBudget;
+}
+
+/// Runs `request` allowing at most `limit` passenger-status runs, however retries and multiple
+/// instances add up. Runs past the limit fail with `QueueError::SpawnLimit`. 0 is no limit.
+/// Background refreshes aren't part of any request, so they're never limited.
+pub async fn limit_spawns<F: Future>(limit: u32, request: F) -> F::Output {
+    if limit == 0 {
+        return request.await;
+    }
+    SPAWN_BUDGET.scope(SpawnBudget { limit, left: Cell::new(limit) }, request).await
+}
+
+/// Uses up one of the current request's runs, if it's limited.
+fn take_spawn() -> Result<(), QueueError> {
+    let exhausted = SPAWN_BUDGET.try_with(|budget| match budget.left.get() {
+        0 => Some(budget.limit),
+        left => {
+            budget.left.set(left - 1);
+            None
+        }
+    });
+    match exhausted {
+        Ok(Some(limit)) => Err(QueueError::SpawnLimit { limit }),
+        _ => Ok(()),
+    }
+}
+
+/// How long passenger-status gets to answer a regular check.
+pub const STATUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A source of passenger-status output. Also implemented for closures returning a future,
+/// so tests and embedding applications can supply their own output.
+/// Errors that are a `QueueError` keep their variant; anything else is reported as `QueueError::Other`.
+pub trait StatusProvider: Send + Sync {
+    fn fetch(&self) -> Pin<Box<dyn Future<Output = anyhow::Result<String>> + Send + '_>>;
+
+    /// Whether each fetch runs a process, and so counts towards `max_total_subprocess_spawns_per_request`.
+    fn spawns(&self) -> bool {
+        true
+    }
+}
+
+impl<F, Fut> StatusProvider for F
+where
+    F: Fn() -> Fut + Send + Sync,
+    Fut: Future<Output = anyhow::Result<String>> + Send + 'static,
+{
+    fn fetch(&self) -> Pin<Box<dyn Future<Output = anyhow::Result<String>> + Send + '_>> {
+        Box::pin(self())
+    }
+}
+
+/// Runs `command`, normally `passenger-status`, against `instance` when set and from
+/// `working_directory` when set.
+pub struct PassengerStatus {
+    /// The program, then its arguments.
+    pub command: Vec<String>,
+    pub timeout: Duration,
+    pub max_output_bytes: usize,
+    pub instance: Option<String>,
+    pub working_directory: Option<String>,
+    /// Log stderr from runs that succeed anyway, rather than ignoring it.
+    pub warn_on_stderr: bool,
+    /// With `json`, the output is a pool document, rewritten as a text report when read.
+    pub format: StatusFormat,
+    /// On a timeout, use what was printed so far if it has the queue line, rather than failing.
+    pub use_partial_output: bool,
+    /// `queue_patterns`, for telling whether partial output has the queue line.
+    pub queue_patterns: Vec<String>,
+}
+
+impl PassengerStatus {
+    /// passenger-status as `settings` configure it, giving up after `timeout`. With several
+    /// `passenger_status_command`s, this runs the first.
+    pub fn new(settings: &Settings, timeout: Duration) -> PassengerStatus {
+        PassengerStatus::running(&status_commands(settings)[0], settings, timeout)
+    }
+
+    /// Like `new`, but running `command`, split on whitespace into the program and its arguments.
+    pub fn running(command: &str, settings: &Settings, timeout: Duration) -> PassengerStatus {
+        PassengerStatus {
+            command: command.split_whitespace().map(String::from).collect(),
+            timeout,
+            max_output_bytes: settings.max_status_output_bytes,
+            instance: settings.passenger_instance.clone().filter(|instance| !instance.is_empty()),
+            working_directory: settings.working_directory.clone().filter(|directory| !directory.is_empty()),
+            warn_on_stderr: settings.warn_on_status_stderr,
+            format: settings.passenger_status_format,
+            use_partial_output: settings.partial_output_on_timeout,
+            queue_patterns: settings.queue_patterns.clone(),
+        }
+    }
+
+    /// Runs passenger-status, giving up after `timeout` or once stdout or stderr grows past
+    /// `max_output_bytes`. The whole output is kept rather than stopping at the queue line, since
+    /// cached output also answers `/health/:app` and `/status`.
+    pub async fn read(&self) -> Result<String, QueueError> {
+        let program = self.command.first().map(String::as_str).unwrap_or("passenger-status");
+        let args = self.args();
+        let mut stdout = Vec::new();
+        let run = run_passenger_status(program, &args, self.max_output_bytes, self.working_directory.as_deref(), self.warn_on_stderr, &mut stdout);
+        let output = match timeout(self.timeout, run).await {
+            Ok(output) => output?,
+            Err(_) if self.use_partial_output => return self.salvage(&stdout),
+            Err(_) => return Err(QueueError::Timeout),
+        };
+        match self.format {
+            StatusFormat::Text => Ok(output),
+            StatusFormat::Json => pool_json::to_status_text(&output),
+        }
+    }
+
+    /// The complete lines passenger-status printed before timing out, if the queue line is among
+    /// them. A pool document can't be read in part, so with `json` it's always a timeout.
+    fn salvage(&self, stdout: &[u8]) -> Result<String, QueueError> {
+        let stdout = String::from_utf8_lossy(stdout);
+        // A cut-off last line could be a queue length missing its last digits
+        let complete = &stdout[..stdout.rfind('\n').map_or(0, |end| end + 1)];
+        if self.format != StatusFormat::Text || parse_queue_length_with(complete, &queue_patterns(&self.queue_patterns)).is_err() {
+            return Err(QueueError::Timeout);
+        }
+        warn!("passenger-status timed out after {:?}, using the queue line it had printed", self.timeout);
+        Ok(complete.to_string())
+    }
+
+    fn args(&self) -> Vec<&str> {
+        let mut args: Vec<&str> = self.command.iter().skip(1).map(String::as_str).collect();
+        if let Some(instance) = &self.instance {
+            args.extend(["--instance", instance]);
+        }
+        args
+    }
+}
+
+impl StatusProvider for PassengerStatus {
+    fn fetch(&self) -> Pin<Box<dyn Future<Output = anyhow::Result<String>> + Send + '_>> {
+        Box::pin(async move { Ok(self.read().await?) })
+    }
+}
+
+/// Reads passenger-status output that something else, like a cron job, wrote to `path`, instead of
+/// running passenger-status. With `max_age`, a file last written longer ago than that is an error.
+pub struct StatusFile {
+    pub path: String,
+    pub max_age: Option<Duration>,
+    pub max_output_bytes: usize,
+    /// With `json`, the file holds a pool document, rewritten as a text report when read.
+    pub format: StatusFormat,
+}
+
+impl StatusFile {
+    /// `passenger_status_file` as `settings` configure it, if set.
+    pub fn new(settings: &Settings) -> Option<StatusFile> {
+        let path = settings.passenger_status_file.clone().filter(|path| !path.is_empty())?;
+        Some(StatusFile {
+            path,
+            max_age: settings.max_file_age_secs.filter(|secs| *secs > 0).map(Duration::from_secs),
+            max_output_bytes: settings.max_status_output_bytes,
+            format: settings.passenger_status_format,
+        })
+    }
+
+    /// Reads the file, parsed like passenger-status' stdout. Bounded by `max_output_bytes` the same way.
+    pub async fn read(&self) -> Result<String, QueueError> {
+        let unreadable = |e: std::io::Error| QueueError::StatusFileUnreadable { path: self.path.clone(), reason: e.to_string() };
+        let file = tokio::fs::File::open(&self.path).await.map_err(unreadable)?;
+        if let Some(max_age) = self.max_age {
+            // A modification time in the future counts as just written
+            let modified = file.metadata().await.and_then(|metadata| metadata.modified()).map_err(unreadable)?;
+            let age = modified.elapsed().unwrap_or_default();
+            if age > max_age {
+                return Err(QueueError::StaleStatusFile { age_secs: age.as_secs(), max_age_secs: max_age.as_secs() });
+            }
+        }
+        let mut output = Vec::new();
+        file.take(self.max_output_bytes as u64 + 1).read_to_end(&mut output).await.map_err(unreadable)?;
+        if output.len() > self.max_output_bytes {
+            return Err(QueueError::OutputTooLarge { limit: self.max_output_bytes });
+        }
+
+        let output = String::from_utf8_lossy(&output).into_owned();
+        if output.contains(NOT_RUNNING_PATTERN) {
+            return Err(QueueError::NotRunning);
+        }
+        match self.format {
+            StatusFormat::Text => Ok(output),
+            StatusFormat::Json => pool_json::to_status_text(&output),
+        }
+    }
+}
+
+impl StatusProvider for StatusFile {
+    fn fetch(&self) -> Pin<Box<dyn Future<Output = anyhow::Result<String>> + Send + '_>> {
+        Box::pin(async move { Ok(self.read().await?) })
+    }
+
+    fn spawns(&self) -> bool {
+        false
+    }
+}
+
+/// Caches passenger-status output, stale-while-revalidate. Output younger than `stale_after_ms` is
+/// served as is; older output is still served but refreshed in the background, until it's older than
+/// `swr_max_stale_ms` and requests wait for a fresh read. A `stale_after_ms` of 0 disables caching.
+pub struct StatusCache {
+    provider: Box<dyn StatusProvider>,
+    cached: Mutex<Option<(Instant, String)>>,
+    refreshing: AtomicBool,
+    // When the provider last ran, for `min_spawn_interval_ms`
+    last_spawned: tokio::sync::Mutex<Option<Instant>>,
+    // The last `error_buffer_size` failed reads, oldest first
+    errors: Mutex<VecDeque<StatusError>>,
+    // The last process count read, and when it changed within `process_churn_window_secs`
+    process_changes: Mutex<(Option<i32>, VecDeque<Instant>)>,
+}
+
+/// A failed read of passenger-status, as listed by `/errors`.
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusError {
+    pub timestamp: u64,
+    /// `QueueError::kind`.
+    pub kind: &'static str,
+    pub message: String,
+}
+
+impl StatusCache {
+    pub fn new(provider: impl StatusProvider + 'static) -> StatusCache {
+        StatusCache {
+            provider: Box::new(provider),
+            cached: Mutex::new(None),
+            refreshing: AtomicBool::new(false),
+            last_spawned: tokio::sync::Mutex::new(None),
+            errors: Mutex::new(VecDeque::new()),
+            process_changes: Mutex::new((None, VecDeque::new())),
+        }
+    }
+
+    /// A cache in front of `passenger-status`, with the regular `STATUS_TIMEOUT`, or in front of
+    /// `passenger_status_file` when that's set.
+    pub fn passenger(settings: &Settings) -> StatusCache {
+        match StatusFile::new(settings) {
+            Some(file) => StatusCache::new(file),
+            None => StatusCache::new(PassengerStatus::new(settings, STATUS_TIMEOUT)),
+        }
+    }
+
+    /// Like `passenger`, but one cache for each `passenger_status_command`, along with the command.
+    /// With `passenger_status_file`, that's the only one, keyed by its path.
+    pub fn per_command(settings: &Settings) -> Vec<(String, StatusCache)> {
+        StatusCache::per_command_within(settings, STATUS_TIMEOUT)
+    }
+
+    /// Like `per_command`, with `timeout` for passenger-status rather than `STATUS_TIMEOUT`.
+    pub fn per_command_within(settings: &Settings, timeout: Duration) -> Vec<(String, StatusCache)> {
+        if let Some(file) = StatusFile::new(settings) {
+            return vec![(file.path.clone(), StatusCache::new(file))];
+        }
+        status_commands(settings)
+            .into_iter()
+            .map(|command| {
+                let status = StatusCache::new(PassengerStatus::running(&command, settings, timeout));
+                (command, status)
+            })
+            .collect()
+    }
+
+    pub async fn read(self: &Arc<Self>, settings: &Settings) -> Result<String, QueueError> {
+        if settings.stale_after_ms == 0 {
+            // Still goes through refresh, so last_fetched stays accurate
+            return self.refresh(settings).await;
+        }
+
+        let stale_after = Duration::from_millis(settings.stale_after_ms);
+        let max_stale = Duration::from_millis(settings.swr_max_stale_ms.max(settings.stale_after_ms));
+        let cached = self.cached.lock().unwrap().clone();
+        if let Some((fetched_at, output)) = cached {
+            let age = fetched_at.elapsed();
+            if age < stale_after {
+                return Ok(output);
+            }
+            if age < max_stale {
+                self.refresh_in_background(settings);
+                return Ok(output);
+            }
+        }
+
+        self.refresh(settings).await
+    }
+
+    /// Reads fresh output whatever the caching settings. `min_spawn_interval_ms` still holds: rather
+    /// than reusing the last output, this waits out the interval. The output is kept for the reads
+    /// after, like any other.
+    pub async fn read_fresh(&self, settings: &Settings) -> Result<String, QueueError> {
+        let min_interval = Duration::from_millis(settings.min_spawn_interval_ms);
+        let output = if min_interval.is_zero() || !self.provider.spawns() {
+            self.fetch(settings, None).await?
+        } else {
+            let mut last_spawned = self.last_spawned.lock().await;
+            self.fetch(settings, Some((&mut last_spawned, min_interval))).await?
+        };
+        self.remember(&output, settings);
+        Ok(output)
+    }
+
+    /// The most recent failed reads, oldest first, including ones that were retried.
+    pub fn errors(&self) -> Vec<StatusError> {
+        self.errors.lock().unwrap().iter().cloned().collect()
+    }
+
+    fn record_error(&self, error: &QueueError, capacity: usize) {
+        let mut errors = self.errors.lock().unwrap();
+        errors.push_back(StatusError {
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0),
+            kind: error.kind(),
+            message: truncate(&error.to_string(), ERROR_MESSAGE_CHARS),
+        });
+        while errors.len() > capacity {
+            errors.pop_front();
+        }
+    }
+
+    /// How many times the process count changed between reads in the last
+    /// `process_churn_window_secs`. Workers that keep crashing and being restarted make it flap.
+    pub fn process_churn(&self, settings: &Settings) -> usize {
+        let mut process_changes = self.process_changes.lock().unwrap();
+        forget_before(&mut process_changes.1, Duration::from_secs(settings.process_churn_window_secs));
+        process_changes.1.len()
+    }
+
+    fn note_process_count(&self, output: &str, settings: &Settings) {
+        let count = match parse_process_count(output) {
+            Ok(count) => count,
+            Err(_) => return,
+        };
+        let mut process_changes = self.process_changes.lock().unwrap();
+        let (last, changes) = &mut *process_changes;
+        if last.is_some_and(|last| last != count) {
+            changes.push_back(Instant::now());
+        }
+        *last = Some(count);
+        forget_before(changes, Duration::from_secs(settings.process_churn_window_secs));
+    }
+
+    /// The output last read successfully and when, whether or not caching is enabled.
+    pub fn last_output(&self) -> Option<(Instant, String)> {
+        self.cached.lock().unwrap().clone()
+    }
+
+    /// When output was last read successfully, whether or not caching is enabled.
+    pub fn last_fetched(&self) -> Option<Instant> {
+        self.cached.lock().unwrap().as_ref().map(|(fetched_at, _)| *fetched_at)
+    }
+
+    /// Reads fresh output, except that with `min_spawn_interval_ms` passenger-status, whatever the
+    /// caching settings, runs at most once per interval: in between, the last output is reused, or
+    /// if there isn't one the read waits out the interval.
+    async fn refresh(&self, settings: &Settings) -> Result<String, QueueError> {
+        let min_interval = Duration::from_millis(settings.min_spawn_interval_ms);
+        let output = if min_interval.is_zero() || !self.provider.spawns() {
+            self.fetch(settings, None).await?
+        } else {
+            // Held through the run, so the requests waiting on it get its output
+            let mut last_spawned = self.last_spawned.lock().await;
+            let cached = self.cached.lock().unwrap().clone();
+            match cached {
+                Some((_, output)) if last_spawned.is_some_and(|at| at.elapsed() < min_interval) => return Ok(output),
+                _ => self.fetch(settings, Some((&mut last_spawned, min_interval))).await?,
+            }
+        };
+        self.remember(&output, settings);
+        Ok(output)
+    }
+
+    fn remember(&self, output: &str, settings: &Settings) {
+        *self.cached.lock().unwrap() = Some((Instant::now(), output.to_string()));
+        self.note_process_count(output, settings);
+    }
+
+    fn refresh_in_background(self: &Arc<Self>, settings: &Settings) {
+        if self.refreshing.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let cache = self.clone();
+        let settings = settings.clone();
+        tokio::spawn(async move {
+            if let Err(e) = cache.refresh(&settings).await {
+                warn!("Background refresh of passenger status failed: {}", e);
+            }
+            cache.refreshing.store(false, Ordering::SeqCst);
+        });
+    }
+
+    /// Reads from the provider, retrying up to `status_retries` times on the failures `retry_on` names.
+    /// With `spacing`, each run waits until the interval has passed since the one before.
+    async fn fetch(&self, settings: &Settings, mut spacing: Option<(&mut Option<Instant>, Duration)>) -> Result<String, QueueError> {
+        let mut retries = 0;
+        loop {
+            if self.provider.spawns() {
+                take_spawn()?;
+            }
+            if let Some((last_spawned, min_interval)) = &mut spacing {
+                if let Some(wait) = last_spawned.and_then(|at| min_interval.checked_sub(at.elapsed())) {
+                    tokio::time::sleep(wait).await;
+                }
+                **last_spawned = Some(Instant::now());
+            }
+            let result = self.provider.fetch().await.map_err(QueueError::from);
+            if let Err(e) = &result {
+                self.record_error(e, settings.error_buffer_size);
+            }
+            match result {
+                Err(e) if retries < settings.status_retries && should_retry(&e, settings.retry_on) => {
+                    retries += 1;
+                    warn!("Reading passenger status failed, retrying ({} of {}): {}", retries, settings.status_retries, e);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Drops the times in `changes` from longer than `window` ago.
+fn forget_before(changes: &mut VecDeque<Instant>, window: Duration) {
+    while changes.front().is_some_and(|changed_at| changed_at.elapsed() > window) {
+        changes.pop_front();
+    }
+}
+
+fn should_retry(error: &QueueError, retry_on: RetryOn) -> bool {
+    match retry_on {
+        RetryOn::Timeout => matches!(error, QueueError::Timeout),
+        RetryOn::All => true,
+        RetryOn::None => false,
+    }
+}
+
+/// The configured `passenger_status_command`s, or just the one that prints
+/// `passenger_status_format`.
+pub fn status_commands(settings: &Settings) -> Vec<String> {
+    if settings.passenger_status_command.is_empty() {
+        let command = match settings.passenger_status_format {
+            StatusFormat::Text => "passenger-status",
+            StatusFormat::Json => "passenger-config api-call get /pool.json",
+        };
+        vec![command.to_string()]
+    } else {
+        settings.passenger_status_command.clone()
+    }
+}
+
+async fn run_passenger_status(
+    program: &str,
+    args: &[&str],
+    max_output_bytes: usize,
+    working_directory: Option<&str>,
+    warn_on_stderr: bool,
+    stdout: &mut Vec<u8>,
+) -> Result<String, QueueError> {
+    let mut command = tokio::process::Command::new(program);
+    if let Some(directory) = working_directory {
+        command.current_dir(directory);
+    }
+    // kill_on_drop, so passenger-status doesn't outlive a timeout or an oversized read
+    let mut child = command
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => QueueError::NotFound,
+            _ => not_run(e),
+        })?;
+    // Read into `stdout` as it arrives, so the caller has whatever was printed if it gives up waiting
+    let child_stdout = child.stdout.take().expect("stdout is piped");
+    let child_stderr = child.stderr.take().expect("stderr is piped");
+    let mut stderr = Vec::new();
+    tokio::try_join!(read_bounded(child_stdout, max_output_bytes, stdout), read_bounded(child_stderr, max_output_bytes, &mut stderr))?;
+    let status = child.wait().await.map_err(not_run)?;
+
+    let stdout = String::from_utf8_lossy(stdout).into_owned();
+    let stderr = String::from_utf8_lossy(&stderr).into_owned();
+    if stdout.contains(NOT_RUNNING_PATTERN) || stderr.contains(NOT_RUNNING_PATTERN) {
+        return Err(QueueError::NotRunning);
+    }
+
+    if status.success() {
+        // Deprecation notices and the like, which may come before a change that does break parsing
+        if warn_on_stderr && !stderr.trim().is_empty() {
+            STDERR_WARNINGS.fetch_add(1, Ordering::Relaxed);
+            warn!("passenger-status succeeded but printed to stderr: {}", truncate(stderr.trim(), STDERR_WARNING_CHARS));
+        }
+        Ok(stdout)
+    } else {
+        Err(QueueError::CommandFailed { code: status.code(), stderr })
+    }
+}
+
+/// The Passenger version from `passenger --version`, for `report_passenger_version`. `None`, with a
+/// warning, when passenger can't be run or doesn't print a version, since the version is only
+/// informational.
+pub async fn passenger_version(settings: &Settings) -> Option<String> {
+    let command = PassengerStatus {
+        instance: None,
+        warn_on_stderr: false,
+        format: StatusFormat::Text,
+        ..PassengerStatus::running(VERSION_COMMAND, settings, STATUS_TIMEOUT)
+    };
+    let output = match command.read().await {
+        Ok(output) => output,
+        Err(QueueError::NotFound) => {
+            warn!("Not reporting the Passenger version, passenger isn't installed or on the PATH");
+            return None;
+        }
+        Err(e) => {
+            warn!("Not reporting the Passenger version, {} failed: {}", VERSION_COMMAND, e);
+            return None;
+        }
+    };
+    let version = parse_passenger_version(&output);
+    if version.is_none() {
+        warn!("Not reporting the Passenger version, {} printed none: {}", VERSION_COMMAND, truncate(output.trim(), UNRECOGNIZED_SAMPLE_CHARS));
+    }
+    version
+}
+
+/// The first dotted version number in `passenger --version` output, like `6.0.18`.
+pub fn parse_passenger_version(output: &str) -> Option<String> {
+    VERSION.captures(output).map(|captures| captures[1].to_string())
+}
+
+/// How many successful passenger-status runs printed to stderr, when `warn_on_status_stderr` is set.
+pub fn stderr_warnings() -> u64 {
+    STDERR_WARNINGS.load(Ordering::Relaxed)
+}
+
+fn not_run(error: std::io::Error) -> QueueError {
+    QueueError::CommandFailed { code: None, stderr: error.to_string() }
+}
+
+/// Reads up to `max_bytes` into `output` a chunk at a time, so it holds everything read so far
+/// even if this is dropped midway.
+async fn read_bounded(reader: impl AsyncRead + Unpin, max_bytes: usize, output: &mut Vec<u8>) -> Result<(), QueueError> {
+    let mut reader = reader.take(max_bytes as u64 + 1);
+    let mut chunk = [0; 8192];
+    loop {
+        let read = reader.read(&mut chunk).await.map_err(not_run)?;
+        if read == 0 {
+            break;
+        }
+        output.extend_from_slice(&chunk[..read]);
+    }
+    if output.len() > max_bytes {
+        return Err(QueueError::OutputTooLarge { limit: max_bytes });
+    }
+    Ok(())
+}
+
+pub fn parse_queue_length(output: &str) -> Result<i32, QueueError> {
+    parse_queue_length_with(output, &[QUEUE_LINE_PATTERN])
+}
+
+/// Like `parse_queue_length`, but reading the queue from the line containing the first of
+/// `patterns` that the output has, for Passenger versions that word it differently.
+pub fn parse_queue_length_with(output: &str, patterns: &[&str]) -> Result<i32, QueueError> {
+    // The line is expected to be something like "Requests in top-level queue : 0"
+    let pattern = match patterns.iter().find(|pattern| output.contains(**pattern)) {
+        Some(pattern) => {
+            debug!("Reading the queue length from the '{}' line", pattern);
+            pattern
+        }
+        None if !output.trim().is_empty() && !output.contains(NOT_RUNNING_PATTERN) => {
+            let sample = truncate(output.trim(), UNRECOGNIZED_SAMPLE_CHARS);
+            if !WARNED_UNRECOGNIZED.swap(true, Ordering::Relaxed) {
+                warn!("unrecognized passenger-status format, there is no '{}' line: {:?}", patterns.join("' or '"), sample);
+            }
+            return Err(QueueError::UnrecognizedFormat { sample });
+        }
+        None => patterns.first().unwrap_or(&QUEUE_LINE_PATTERN),
+    };
+    let queue_length = parse_count(output, pattern);
+    if queue_length.is_ok() {
+        WARNED_UNRECOGNIZED.store(false, Ordering::Relaxed);
+    }
+    queue_length
+}
+
+/// The patterns to look for the queue line with: `queue_patterns` in order, then Passenger's own
+/// wording, which `passenger_status_format = "json"` output always uses.
+pub fn queue_patterns(configured: &[String]) -> Vec<&str> {
+    let mut patterns: Vec<&str> = configured.iter().map(String::as_str).collect();
+    if !patterns.contains(&QUEUE_LINE_PATTERN) {
+        patterns.push(QUEUE_LINE_PATTERN);
+    }
+    patterns
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+pub fn parse_process_count(output: &str) -> Result<i32, QueueError> {
+    // The line is expected to be something like "Processes     : 2"
+    parse_count(output, PROCESSES_LINE_PATTERN)
+}
+
+pub fn parse_max_pool_size(output: &str) -> Result<i32, QueueError> {
+    // The line is expected to be something like "Max pool size : 6"
+    parse_count(output, MAX_POOL_SIZE_LINE_PATTERN)
+}
+
+/// Queue lengths of each application group, keyed by the last component of the group's path,
+/// so `/var/www/api (production)` is `api`.
+pub fn parse_app_queues(output: &str) -> Result<HashMap<String, i32>, QueueError> {
+    let mut queues = HashMap::new();
+    let mut current_app = None;
+    for line in output.lines() {
+        // Group headers are the only unindented lines ending in a colon, e.g. "/var/www/api (production):"
+        if !line.starts_with(char::is_whitespace) && line.trim_end().ends_with(':') {
+            current_app = Some(app_name(line));
+        } else if line.contains(APP_QUEUE_LINE_PATTERN) {
+            if let Some(app) = current_app.take() {
+                queues.insert(app, parse_line_number(line, APP_QUEUE_LINE_PATTERN)?);
+            }
+        }
+    }
+    Ok(queues)
+}
+
+/// Requests being handled right now: the sum of every process' `Sessions`.
+pub fn parse_in_flight(output: &str) -> Result<i32, QueueError> {
+    if output.contains(NOT_RUNNING_PATTERN) {
+        return Err(QueueError::NotRunning);
+    }
+    let mut in_flight = 0;
+    for captures in output.lines().filter_map(|line| PROCESS_SESSIONS.captures(line)) {
+        in_flight += parse_number(&captures[1]).map_err(|reason| QueueError::ParseFailed { reason, raw: captures[0].to_string() })?;
+    }
+    Ok(in_flight)
+}
+
+/// How many processes have no sessions, so could take a request straight away.
+pub fn parse_idle_process_count(output: &str) -> Result<i32, QueueError> {
+    if output.contains(NOT_RUNNING_PATTERN) {
+        return Err(QueueError::NotRunning);
+    }
+    let mut idle = 0;
+    for captures in output.lines().filter_map(|line| PROCESS_SESSIONS.captures(line)) {
+        let sessions = parse_number(&captures[1]).map_err(|reason| QueueError::ParseFailed { reason, raw: captures[0].to_string() })?;
+        if sessions == 0 {
+            idle += 1;
+        }
+    }
+    Ok(idle)
+}
+
+/// Whether `output` lists processes with their sessions, without which `parse_idle_process_count`
+/// can't tell none being idle from not knowing.
+pub fn lists_process_sessions(output: &str) -> bool {
+    output.lines().any(|line| PROCESS_SESSIONS.is_match(line))
+}
+
+/// Memory use of each process in megabytes, as `(pid, memory_mb)` in the order they're listed.
+pub fn parse_process_memory(output: &str) -> Vec<(u32, u64)> {
+    let mut processes = Vec::new();
+    let mut current_pid = None;
+    for line in output.lines() {
+        if let Some(captures) = PROCESS_PID.captures(line) {
+            current_pid = captures[1].parse().ok();
+        }
+        if let Some(captures) = PROCESS_MEMORY.captures(line) {
+            if let (Some(pid), Ok(amount)) = (current_pid.take(), captures[1].parse::<f64>()) {
+                let megabytes = match &captures[2] {
+                    "K" => amount / 1024.0,
+                    "G" => amount * 1024.0,
+                    _ => amount,
+                };
+                processes.push((pid, megabytes.round() as u64));
+            }
+        }
+    }
+    processes
+}
+
+fn app_name(group_header: &str) -> String {
+    let group = group_header.trim_end().trim_end_matches(':');
+    let path = group.split(" (").next().unwrap_or(group);
+    path.trim_end_matches('/').rsplit('/').next().unwrap_or(path).to_string()
+}
+
+fn parse_count(output: &str, pattern: &str) -> Result<i32, QueueError> {
+    if output.contains(NOT_RUNNING_PATTERN) {
+        return Err(QueueError::NotRunning);
+    }
+
+    let line = output.lines().find(|line| line.contains(pattern)).ok_or_else(|| QueueError::ParseFailed {
+        reason: format!("Failed to find '{}' in passenger-status output", pattern),
+        raw: output.to_string(),
+    })?;
+
+    parse_line_number(line, pattern)
+}
+
+fn parse_line_number(line: &str, pattern: &str) -> Result<i32, QueueError> {
+    let reason = match TRAILING_NUMBER.captures(line) {
+        Some(captures) => match parse_number(&captures[1]) {
+            // A count can't be negative, so this is corrupt output rather than lots of headroom
+            Ok(number) if number < 0 => format!("'{}' line has a negative count: {}", pattern, line.trim()),
+            Ok(number) => return Ok(number),
+            Err(reason) => reason,
+        },
+        None => format!("Failed to parse '{}' line: {}", pattern, line.trim()),
+    };
+    Err(QueueError::ParseFailed { reason, raw: line.to_string() })
+}
+
+/// Parses a number that may have thousands separators or a fractional part, rounding to the nearest integer.
+fn parse_number(value: &str) -> Result<i32, String> {
+    let number = value
+        .replace(',', "")
+        .parse::<f64>()
+        .map_err(|e| format!("Failed to parse '{}' as a number: {}", value, e))?
+        .round();
+    if number < i32::MIN as f64 || number > i32::MAX as f64 {
+        return Err(format!("'{}' is out of range", value));
+    }
+    Ok(number as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::check::Decision;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn parses_queue_length_from_full_passenger_status_output() {
+        let output = include_str!("../samples/passenger-5-busy.txt");
+        assert_eq!(parse_queue_length(output).unwrap(), 12);
+    }
+
+    #[test]
+    fn parses_the_passenger_version() {
+        assert_eq!(parse_passenger_version("Phusion Passenger(R) 6.0.18\n").as_deref(), Some("6.0.18"));
+        assert_eq!(parse_passenger_version("Phusion Passenger Enterprise 5.3.7").as_deref(), Some("5.3.7"));
+        assert_eq!(parse_passenger_version("passenger: command not found"), None);
+    }
+
+    #[test]
+    fn fails_to_parse_output_without_queue_line() {
+        assert!(parse_queue_length("Version : 6.0.17\nProcesses : 2\n").is_err());
+    }
+
+    #[test]
+    fn uses_the_first_queue_pattern_the_output_has() {
+        let output = "Version : 7.0.0\nQueued requests : 2\nRequests waiting : 5\n";
+        assert_eq!(parse_queue_length_with(output, &["Requests in top-level queue", "Queued requests", "Requests waiting"]).unwrap(), 2);
+        assert!(matches!(parse_queue_length_with(output, &["Requests in top-level queue"]), Err(QueueError::UnrecognizedFormat { .. })));
+
+        let configured = ["Queued requests".to_string()];
+        assert_eq!(queue_patterns(&configured), vec!["Queued requests", "Requests in top-level queue"]);
+        assert_eq!(parse_queue_length_with("Requests in top-level queue : 4\n", &queue_patterns(&configured)).unwrap(), 4);
+    }
+
+    #[test]
+    fn output_without_queue_line_is_an_unrecognized_format() {
+        let error = parse_queue_length("Version : 7.0.0\nQueued requests : 2\n").unwrap_err();
+        assert!(matches!(error, QueueError::UnrecognizedFormat { sample } if sample == "Version : 7.0.0\nQueued requests : 2"));
+
+        let error = parse_queue_length(&"x".repeat(500)).unwrap_err();
+        assert!(matches!(error, QueueError::UnrecognizedFormat { sample } if sample == format!("{}...", "x".repeat(200))));
+
+        assert!(matches!(parse_queue_length(" \n").unwrap_err(), QueueError::ParseFailed { .. }));
+    }
+
+    #[test]
+    fn parses_queue_line_containing_extra_colons() {
+        let output = "2024-03-01 12:00:00 +0000 Requests in top-level queue : 4\n";
+        assert_eq!(parse_queue_length(output).unwrap(), 4);
+
+        let output = "Requests in top-level queue (as of 12:00:00): 7  \n";
+        assert_eq!(parse_queue_length(output).unwrap(), 7);
+    }
+
+    #[test]
+    fn fails_to_parse_queue_line_without_trailing_number() {
+        assert!(parse_queue_length("Requests in top-level queue : unknown\n").is_err());
+    }
+
+    #[test]
+    fn parses_queue_length_with_separators_and_fractions() {
+        assert_eq!(parse_queue_length("Requests in top-level queue : 1,234\n").unwrap(), 1234);
+        assert_eq!(parse_queue_length("Requests in top-level queue : 0.0\n").unwrap(), 0);
+        assert_eq!(parse_queue_length("Requests in top-level queue : 2.6\n").unwrap(), 3);
+    }
+
+    #[test]
+    fn parses_queue_length_of_each_app_group() {
+        let queues = parse_app_queues(include_str!("../samples/passenger-6-multi-app.txt")).unwrap();
+
+        assert_eq!(queues.len(), 2);
+        assert_eq!(queues["api"], 2);
+        assert_eq!(queues["admin"], 0);
+    }
+
+    #[test]
+    fn recognizes_passenger_not_running() {
+        let error = parse_queue_length(include_str!("../samples/passenger-not-running.txt")).unwrap_err();
+        assert!(matches!(error, QueueError::NotRunning));
+    }
+
+    #[test]
+    fn selects_the_configured_instance() {
+        let status = PassengerStatus::new(&Settings::default(), STATUS_TIMEOUT);
+        assert!(status.args().is_empty());
+
+        let settings = Settings { passenger_instance: Some("pXv1Jd0q".to_string()), ..Settings::default() };
+        let status = PassengerStatus::new(&settings, STATUS_TIMEOUT);
+        assert_eq!(status.args(), ["--instance", "pXv1Jd0q"]);
+    }
+
+    #[test]
+    fn runs_each_configured_command() {
+        let settings = Settings {
+            passenger_status_command: vec!["/opt/b/bin/passenger-status --verbose".to_string()],
+            passenger_instance: Some("pXv1Jd0q".to_string()),
+            ..Settings::default()
+        };
+        let status = PassengerStatus::new(&settings, STATUS_TIMEOUT);
+        assert_eq!(status.command[0], "/opt/b/bin/passenger-status");
+        assert_eq!(status.args(), ["--verbose", "--instance", "pXv1Jd0q"]);
+        assert_eq!(PassengerStatus::new(&Settings::default(), STATUS_TIMEOUT).command, ["passenger-status"]);
+    }
+
+    #[tokio::test]
+    async fn reads_the_pool_as_json_when_asked() {
+        let json = Settings { passenger_status_format: StatusFormat::Json, ..Settings::default() };
+        assert_eq!(PassengerStatus::new(&json, STATUS_TIMEOUT).command, ["passenger-config", "api-call", "get", "/pool.json"]);
+
+        let sample = format!("cat {}/samples/passenger-6-pool.json", env!("CARGO_MANIFEST_DIR"));
+        let output = PassengerStatus::running(&sample, &json, STATUS_TIMEOUT).read().await.unwrap();
+        assert_eq!(parse_queue_length(&output).unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn reads_a_status_file_instead_of_running_passenger_status() {
+        let path = std::env::temp_dir().join(format!("passenger-ready-{}-status.txt", std::process::id()));
+        std::fs::write(&path, include_str!("../samples/passenger-5-busy.txt")).unwrap();
+        let settings = Settings {
+            passenger_status_file: Some(path.to_string_lossy().into_owned()),
+            max_file_age_secs: Some(60),
+            stale_after_ms: 0,
+            ..Settings::default()
+        };
+        let cache = Arc::new(StatusCache::passenger(&settings));
+        let output = limit_spawns(1, async { (cache.read(&settings).await, cache.read(&settings).await) }).await;
+        assert_eq!(parse_queue_length(&output.1.unwrap()).unwrap(), 12);
+
+        let written = std::time::SystemTime::now() - Duration::from_secs(120);
+        std::fs::File::options().write(true).open(&path).unwrap().set_modified(written).unwrap();
+        let error = StatusFile::new(&settings).unwrap().read().await.unwrap_err();
+        assert!(matches!(error, QueueError::StaleStatusFile { max_age_secs: 60, .. }), "{}", error);
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(StatusFile::new(&settings).unwrap().read().await, Err(QueueError::StatusFileUnreadable { .. })));
+    }
+
+    #[tokio::test]
+    async fn counts_stderr_from_successful_runs_when_asked() {
+        let script = "echo 'Requests in top-level queue : 3'; echo 'DEPRECATION: --show' >&2";
+        let before = stderr_warnings();
+        let output = run_passenger_status("sh", &["-c", script], 1024, None, false, &mut Vec::new()).await.unwrap();
+        assert_eq!(parse_queue_length(&output).unwrap(), 3);
+        assert_eq!(stderr_warnings(), before);
+
+        let output = run_passenger_status("sh", &["-c", script], 1024, None, true, &mut Vec::new()).await.unwrap();
+        assert_eq!(parse_queue_length(&output).unwrap(), 3);
+        assert_eq!(stderr_warnings(), before + 1);
+    }
+
+    #[test]
+    fn runs_from_the_configured_working_directory() {
+        let settings = Settings { working_directory: Some("/var/www/app".to_string()), ..Settings::default() };
+        assert_eq!(PassengerStatus::new(&settings, STATUS_TIMEOUT).working_directory.as_deref(), Some("/var/www/app"));
+        assert_eq!(PassengerStatus::new(&Settings::default(), STATUS_TIMEOUT).working_directory, None);
+    }
+
+    #[test]
+    fn sums_sessions_across_processes() {
+        assert_eq!(parse_in_flight(include_str!("../samples/passenger-6-multi-app.txt")).unwrap(), 3);
+        assert_eq!(parse_in_flight(include_str!("../samples/passenger-6-idle.txt")).unwrap(), 0);
+    }
+
+    #[test]
+    fn counts_processes_without_sessions() {
+        assert_eq!(parse_idle_process_count(include_str!("../samples/passenger-6-multi-app.txt")).unwrap(), 2);
+        assert_eq!(parse_idle_process_count(include_str!("../samples/passenger-5-busy.txt")).unwrap(), 0);
+    }
+
+    #[test]
+    fn parses_process_memory() {
+        let memory = parse_process_memory(include_str!("../samples/passenger-6-multi-app.txt"));
+        assert_eq!(memory.len(), 5);
+        assert_eq!(memory[0], (4102, 220));
+        assert_eq!(memory[4], (4152, 176));
+        assert_eq!(parse_process_memory("  * PID: 1  Sessions: 0\n    CPU: 0%  Memory  : 1.5G\n"), vec![(1, 1536)]);
+    }
+
+    #[test]
+    fn rejects_negative_counts() {
+        let error = parse_queue_length("Requests in top-level queue : -5\n").unwrap_err();
+        assert!(matches!(error, QueueError::ParseFailed { .. }));
+        assert!(!Decision::from_queue(Err(error), &Settings::default()).healthy);
+    }
+
+    #[test]
+    fn parses_process_count() {
+        let output = include_str!("../samples/passenger-6-multi-app.txt");
+        assert_eq!(parse_process_count(output).unwrap(), 5);
+    }
+
+    /// A cache whose fetches return an increasing queue length, so tests can tell reads apart.
+    fn counting_cache() -> (Arc<StatusCache>, Arc<AtomicUsize>) {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let cache = StatusCache::new(move || {
+            let count = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            async move { Ok(format!("Requests in top-level queue : {}", count)) }
+        });
+        (Arc::new(cache), fetches)
+    }
+
+    /// A cache whose every fetch fails with `timed_out` deciding the kind of failure.
+    fn failing_cache(timed_out: bool) -> (Arc<StatusCache>, Arc<AtomicUsize>) {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let cache = StatusCache::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if timed_out {
+                    Err(QueueError::Timeout.into())
+                } else {
+                    Err(anyhow::anyhow!("passenger-status execution failed"))
+                }
+            }
+        });
+        (Arc::new(cache), fetches)
+    }
+
+    fn retrying(status_retries: u32, retry_on: RetryOn) -> Settings {
+        Settings { status_retries, retry_on, ..Settings::default() }
+    }
+
+    #[tokio::test]
+    async fn retries_timeouts_by_default() {
+        let (cache, fetches) = failing_cache(true);
+        assert!(cache.read(&retrying(2, RetryOn::Timeout)).await.is_err());
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+
+        let (cache, fetches) = failing_cache(true);
+        assert!(cache.read(&retrying(2, RetryOn::None)).await.is_err());
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn caps_runs_across_a_whole_request() {
+        let (first, first_fetches) = failing_cache(true);
+        let (second, second_fetches) = failing_cache(true);
+        let settings = retrying(4, RetryOn::Timeout);
+
+        let (first_result, second_result) = limit_spawns(3, async { (first.read(&settings).await, second.read(&settings).await) }).await;
+        assert!(matches!(first_result, Err(QueueError::SpawnLimit { limit: 3 })));
+        assert!(matches!(second_result, Err(QueueError::SpawnLimit { limit: 3 })));
+        assert_eq!(first_fetches.load(Ordering::SeqCst) + second_fetches.load(Ordering::SeqCst), 3);
+
+        let (cache, fetches) = failing_cache(true);
+        assert!(matches!(limit_spawns(0, cache.read(&settings)).await, Err(QueueError::Timeout)));
+        assert_eq!(fetches.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn spawns_at_most_once_per_min_spawn_interval() {
+        let (cache, fetches) = counting_cache();
+        let settings = Settings { min_spawn_interval_ms: 200, ..Settings::default() };
+
+        let reads = futures_util::future::join_all((0..10).map(|_| queue_length(&cache, &settings))).await;
+        assert_eq!(reads, vec![1; 10]);
+        assert_eq!(queue_length(&cache, &settings).await, 1);
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert_eq!(queue_length(&cache, &settings).await, 2);
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn fresh_reads_keep_to_min_spawn_interval() {
+        let (cache, fetches) = counting_cache();
+        let settings = Settings { min_spawn_interval_ms: 200, ..Settings::default() };
+
+        let started = Instant::now();
+        cache.read_fresh(&settings).await.unwrap();
+        assert_eq!(queue_length(&cache, &settings).await, 1);
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        cache.read_fresh(&settings).await.unwrap();
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn spaces_out_retries_by_min_spawn_interval() {
+        let (cache, fetches) = failing_cache(true);
+        let settings = Settings { min_spawn_interval_ms: 100, ..retrying(2, RetryOn::Timeout) };
+
+        let started = Instant::now();
+        assert!(cache.read(&settings).await.is_err());
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn retries_other_failures_only_when_retrying_on_all() {
+        let (cache, fetches) = failing_cache(false);
+        assert!(cache.read(&retrying(2, RetryOn::Timeout)).await.is_err());
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        let (cache, fetches) = failing_cache(false);
+        assert!(cache.read(&retrying(2, RetryOn::All)).await.is_err());
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+    }
+
+    async fn queue_length(cache: &Arc<StatusCache>, settings: &Settings) -> i32 {
+        parse_queue_length(&cache.read(settings).await.unwrap()).unwrap()
+    }
+
+    fn caching(stale_after_ms: u64, swr_max_stale_ms: u64) -> Settings {
+        Settings { stale_after_ms, swr_max_stale_ms, ..Settings::default() }
+    }
+
+    #[tokio::test]
+    async fn cache_serves_fresh_output_without_fetching() {
+        let (cache, fetches) = counting_cache();
+        let settings = caching(60_000, 120_000);
+
+        assert_eq!(queue_length(&cache, &settings).await, 1);
+        assert_eq!(queue_length(&cache, &settings).await, 1);
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn cache_serves_stale_output_and_refreshes_in_background() {
+        let (cache, fetches) = counting_cache();
+        let settings = caching(10, 60_000);
+
+        assert_eq!(queue_length(&cache, &settings).await, 1);
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        // Stale, so the old value comes back straight away while a refresh runs
+        assert_eq!(queue_length(&cache, &settings).await, 1);
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+        assert_eq!(queue_length(&cache, &settings).await, 2);
+    }
+
+    #[tokio::test]
+    async fn cache_waits_for_fresh_output_past_max_staleness() {
+        let (cache, fetches) = counting_cache();
+        let settings = caching(10, 20);
+
+        assert_eq!(queue_length(&cache, &settings).await, 1);
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        assert_eq!(queue_length(&cache, &settings).await, 2);
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn cache_is_bypassed_when_disabled() {
+        let (cache, fetches) = counting_cache();
+        let settings = Settings::default();
+
+        assert_eq!(queue_length(&cache, &settings).await, 1);
+        assert_eq!(queue_length(&cache, &settings).await, 2);
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn bounded_read_takes_output_up_to_the_limit() {
+        let output: &[u8] = b"Requests in top-level queue : 0\n";
+        let mut read = Vec::new();
+        read_bounded(output, output.len(), &mut read).await.unwrap();
+        assert_eq!(read, output);
+    }
+
+    #[tokio::test]
+    async fn bounded_read_fails_past_the_limit() {
+        let output: &[u8] = b"Requests in top-level queue : 0\n";
+        assert!(read_bounded(output, output.len() - 1, &mut Vec::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn uses_the_queue_line_printed_before_a_timeout_when_asked() {
+        let settings = Settings { partial_output_on_timeout: true, ..Settings::default() };
+        let slow = |script: &str| PassengerStatus {
+            command: vec!["sh".to_string(), "-c".to_string(), script.to_string()],
+            ..PassengerStatus::running("sh", &settings, Duration::from_millis(300))
+        };
+
+        let output = slow("echo 'Processes : 2'; echo 'Requests in top-level queue : 12'; printf 'Requests in queue: 1'; sleep 5").read().await.unwrap();
+        assert_eq!(parse_queue_length(&output).unwrap(), 12);
+        assert!(!output.contains("Requests in queue"));
+
+        // Cut off mid-line, the queue length could be missing digits
+        let cut_off = slow("echo 'Processes : 2'; printf 'Requests in top-level queue : 1'; sleep 5").read().await;
+        assert!(matches!(cut_off, Err(QueueError::Timeout)));
+
+        let without = PassengerStatus { use_partial_output: false, ..slow("echo 'Requests in top-level queue : 12'; sleep 5") };
+        assert!(matches!(without.read().await, Err(QueueError::Timeout)));
+    }
+}
@@ -0,0 +1,110 @@
+//! Reads Passenger's pool as JSON, from `passenger-config api-call get /pool.json`, for
+//! `passenger_status_format = "json"`. The pool is rewritten as the lines of passenger-status'
+//! text report that the parsers in `passenger` look for, so every check works the same either way.
+
+use crate::error::QueueError;
+use serde_json::Value;
+use std::fmt::Write;
+
+/// The text report for a pool document. Only the top-level queue is required; the pool size,
+/// process count, groups and processes are included when the document has them, since their
+/// fields differ between Passenger versions.
+pub fn to_status_text(json: &str) -> Result<String, QueueError> {
+    let pool: Value = serde_json::from_str(json).map_err(|e| QueueError::ParseFailed {
+        reason: format!("passenger pool JSON is invalid: {}", e),
+        raw: json.to_string(),
+    })?;
+    let queue_length = wait_list_size(&pool).ok_or_else(|| QueueError::UnrecognizedFormat {
+        sample: json.chars().take(200).collect(),
+    })?;
+
+    let mut text = String::new();
+    if let Some(max) = number(&pool["max"]) {
+        writeln!(text, "Max pool size : {}", max).unwrap();
+    }
+    if let Some(processes) = number(&pool["process_count"]) {
+        writeln!(text, "Processes     : {}", processes).unwrap();
+    }
+    writeln!(text, "Requests in top-level queue : {}", queue_length).unwrap();
+
+    for (name, supergroup) in supergroups(&pool) {
+        // Older versions have the processes on the supergroup itself
+        let group = supergroup.get("group").unwrap_or(supergroup);
+        writeln!(text, "\n{}:", name).unwrap();
+        if let Some(queue_length) = wait_list_size(group) {
+            writeln!(text, "  Requests in queue: {}", queue_length).unwrap();
+        }
+        for process in group["processes"].as_array().into_iter().flatten() {
+            let pid = match number(&process["pid"]) {
+                Some(pid) => pid,
+                None => continue,
+            };
+            writeln!(text, "  * PID: {}   Sessions: {}", pid, number(&process["sessions"]).unwrap_or(0)).unwrap();
+            if let Some(kilobytes) = number(&process["real_memory"]) {
+                writeln!(text, "    Memory  : {}M", kilobytes / 1024).unwrap();
+            }
+        }
+    }
+    Ok(text)
+}
+
+/// `get_wait_list_size`, or the length of `get_wait_list` when only that is there.
+fn wait_list_size(value: &Value) -> Option<i64> {
+    number(&value["get_wait_list_size"]).or_else(|| value["get_wait_list"].as_array().map(|list| list.len() as i64))
+}
+
+/// The supergroups and their names, which are an object keyed by name in current versions and a
+/// list of objects with a `name` in others.
+fn supergroups(pool: &Value) -> Vec<(String, &Value)> {
+    match &pool["supergroups"] {
+        Value::Object(supergroups) => supergroups.iter().map(|(name, supergroup)| (name.clone(), supergroup)).collect(),
+        Value::Array(supergroups) => supergroups
+            .iter()
+            .filter_map(|supergroup| Some((supergroup["name"].as_str()?.to_string(), supergroup)))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// A count, which some versions write as a string.
+fn number(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(number) => number.as_i64(),
+        Value::String(number) => number.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::passenger::{parse_app_queues, parse_in_flight, parse_max_pool_size, parse_process_count, parse_process_memory, parse_queue_length};
+
+    #[test]
+    fn reads_the_pool_like_a_text_report() {
+        let text = to_status_text(include_str!("../samples/passenger-6-pool.json")).unwrap();
+
+        assert_eq!(parse_queue_length(&text).unwrap(), 4);
+        assert_eq!(parse_max_pool_size(&text).unwrap(), 6);
+        assert_eq!(parse_process_count(&text).unwrap(), 3);
+        assert_eq!(parse_in_flight(&text).unwrap(), 2);
+        let queues = parse_app_queues(&text).unwrap();
+        assert_eq!((queues["shop"], queues["api"]), (3, 1));
+        assert_eq!(parse_process_memory(&text), vec![(10745, 152), (10768, 148)]);
+    }
+
+    #[test]
+    fn tolerates_missing_and_differently_shaped_fields() {
+        let text = to_status_text(r#"{"get_wait_list": [{}, {}], "supergroups": [{"name": "/srv/app (staging)", "processes": [{"pid": "7", "sessions": "1"}]}]}"#).unwrap();
+
+        assert_eq!(parse_queue_length(&text).unwrap(), 2);
+        assert!(parse_max_pool_size(&text).is_err());
+        assert_eq!(parse_in_flight(&text).unwrap(), 1);
+    }
+
+    #[test]
+    fn rejects_documents_without_a_queue() {
+        assert!(matches!(to_status_text(r#"{"max": 6}"#), Err(QueueError::UnrecognizedFormat { .. })));
+        assert!(matches!(to_status_text("Requests in top-level queue : 0"), Err(QueueError::ParseFailed { .. })));
+    }
+}
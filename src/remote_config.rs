@@ -0,0 +1,113 @@
+use crate::settings::Overrides;
+use hyper::body::HttpBody;
+use hyper::{Client, Uri};
+use serde_json::Value;
+use std::time::Duration;
+use tokio::time::timeout;
+
+// How long to wait for `remote_config_url`, so a slow control plane doesn't hold up startup.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Settings documents are small; anything bigger is a misconfigured URL.
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// Fetches the JSON object at `url` and turns it into `--set` style overrides, so it's merged and
+/// validated exactly like the command line. Nested objects like `{"endpoints": {"status": true}}`
+/// become dotted keys and arrays comma-separated values.
+pub async fn fetch(url: &str) -> Result<Overrides, String> {
+    let uri: Uri = url.parse().map_err(|e| format!("invalid remote_config_url: {}", e))?;
+    let body = timeout(FETCH_TIMEOUT, get(uri))
+        .await
+        .map_err(|_| format!("no response within {}s", FETCH_TIMEOUT.as_secs()))??;
+    let json: Value = serde_json::from_slice(&body).map_err(|e| format!("not JSON: {}", e))?;
+    let mut overrides = Vec::new();
+    match json {
+        Value::Object(_) => flatten("", &json, &mut overrides),
+        _ => return Err("not a JSON object".to_string()),
+    }
+    Ok(overrides)
+}
+
+async fn get(uri: Uri) -> Result<Vec<u8>, String> {
+    let mut res = Client::new().get(uri).await.map_err(|e| e.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("status {}", res.status()));
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = res.body_mut().data().await {
+        body.extend_from_slice(&chunk.map_err(|e| e.to_string())?);
+        if body.len() > MAX_BODY_BYTES {
+            return Err(format!("response is over {} bytes", MAX_BODY_BYTES));
+        }
+    }
+    Ok(body)
+}
+
+fn flatten(key: &str, value: &Value, overrides: &mut Overrides) {
+    match value {
+        Value::Object(fields) => {
+            for (field, value) in fields {
+                let key = if key.is_empty() { field.clone() } else { format!("{}.{}", key, field) };
+                flatten(&key, value, overrides);
+            }
+        }
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(scalar).collect();
+            overrides.push((key.to_string(), items.join(",")));
+        }
+        // Unsetting isn't possible through overrides, so `null` leaves the local value alone
+        Value::Null => {}
+        _ => overrides.push((key.to_string(), scalar(value))),
+    }
+}
+
+fn scalar(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::Filter;
+
+    async fn serve(body: &'static str) -> String {
+        let route = warp::path("settings").map(move || warp::reply::with_header(body, "content-type", "application/json"));
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        format!("http://{}/settings", addr)
+    }
+
+    #[tokio::test]
+    async fn turns_json_into_overrides() {
+        let url = serve(r#"{"max_queue_length": 50, "instance_label": "web-1", "endpoints": {"status": true}, "admin_allow_cidrs": ["10.0.0.0/8", "127.0.0.1"]}"#).await;
+
+        let mut overrides = fetch(&url).await.unwrap();
+        overrides.sort();
+        assert_eq!(
+            overrides,
+            vec![
+                ("admin_allow_cidrs".to_string(), "10.0.0.0/8,127.0.0.1".to_string()),
+                ("endpoints.status".to_string(), "true".to_string()),
+                ("instance_label".to_string(), "web-1".to_string()),
+                ("max_queue_length".to_string(), "50".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_documents_that_are_not_objects() {
+        let url = serve("[1, 2]").await;
+        assert_eq!(fetch(&url).await.unwrap_err(), "not a JSON object");
+    }
+
+    #[tokio::test]
+    async fn reports_unreachable_urls() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/settings", listener.local_addr().unwrap());
+        drop(listener);
+        assert!(fetch(&url).await.is_err());
+    }
+}
@@ -0,0 +1,1210 @@
+use crate::access_log::{self, AccessLogLine};
+use crate::check::{
+    can_all_take_more_traffic, can_take_more_traffic, capacity_headroom, check_app, check_deadline, check_readiness, forced, get_queue_length,
+    within_deadline, Decision, EveryNthCheck, Reason, ReloadGrace, SampleWindow, Warmup,
+};
+use crate::error::StartError;
+#[cfg(feature = "full")]
+use crate::fifo::StateChanges;
+use crate::listener::{limit_connections, systemd_listener, LimitedConnection, Listener};
+#[cfg(feature = "full")]
+use crate::metrics;
+use crate::passenger::{limit_spawns, StatusCache};
+use crate::remote_config;
+use crate::settings::{check_threshold_ratio, load_settings_strictly, Overrides, Settings};
+#[cfg(feature = "full")]
+use crate::statsd;
+use futures_util::future;
+use hyper::ext::ReasonPhrase;
+use hyper::server::accept;
+use hyper::service::{make_service_fn, service_fn, Service};
+use hyper::{Body, Request};
+use log::{info, warn};
+use std::convert::Infallible;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::timeout;
+use warp::http::header::{HeaderMap, HeaderName};
+use warp::http::{HeaderValue, StatusCode};
+use warp::{Filter, Rejection, Reply};
+
+#[cfg(feature = "full")]
+mod diagnostics;
+mod envelope;
+
+// How often `wait_for_passenger_on_startup` tries passenger-status
+const STARTUP_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+pub use envelope::ErrorCode;
+
+/// State shared by all requests.
+pub struct AppState {
+    settings: RwLock<Settings>,
+    warmup: Warmup,
+    #[cfg(feature = "full")]
+    history: diagnostics::History,
+    samples: SampleWindow,
+    every_nth: EveryNthCheck,
+    reload_grace: ReloadGrace,
+    #[cfg(feature = "full")]
+    state_changes: StateChanges,
+    #[cfg(feature = "full")]
+    warn_band: metrics::WarnBand,
+    #[cfg(feature = "full")]
+    apdex: metrics::Apdex,
+    #[cfg(feature = "full")]
+    request_durations: metrics::RequestDurations,
+    // From `passenger --version` at startup, with `report_passenger_version`
+    #[cfg(feature = "full")]
+    passenger_version: std::sync::OnceLock<String>,
+    // The first `passenger_status_command`'s, which everything but `/health` and `/status` reads
+    status: Arc<StatusCache>,
+    // Every `passenger_status_command`'s, including the first, when there's more than one
+    instances: Vec<(String, Arc<StatusCache>)>,
+    // The latest settings from `remote_config_url`, kept so a SIGHUP reload doesn't drop them
+    remote_overrides: Mutex<Overrides>,
+    // The ratio last set through `PUT /threshold` or read from `persist_threshold_file`, likewise
+    threshold_override: Mutex<Option<f64>>,
+    // The `--set` pairs `run` was started with, for `/config/sources`
+    cli_overrides: Mutex<Overrides>,
+    // Held while a SIGHUP or remote config reload loads and applies settings, so they take turns
+    reloading: Mutex<()>,
+}
+
+impl AppState {
+    pub fn new(settings: Settings) -> AppState {
+        let instances = StatusCache::per_command(&settings);
+        AppState::with_instances(settings, instances)
+    }
+
+    pub fn with_status_cache(settings: Settings, status: StatusCache) -> AppState {
+        AppState::with_instances(settings, vec![("passenger-status".to_string(), status)])
+    }
+
+    /// State reading each instance's status from its cache, keyed by the command it runs.
+    pub fn with_instances(settings: Settings, instances: Vec<(String, StatusCache)>) -> AppState {
+        let mut instances: Vec<(String, Arc<StatusCache>)> =
+            instances.into_iter().map(|(command, status)| (command, Arc::new(status))).collect();
+        let status = instances.first().map(|(_, status)| status.clone()).expect("at least one instance");
+        if instances.len() == 1 {
+            instances.clear();
+        }
+        #[cfg(feature = "full")]
+        let history_size = if settings.endpoints.history { settings.history_size } else { 0 };
+        AppState {
+            warmup: Warmup::new(&settings),
+            #[cfg(feature = "full")]
+            history: diagnostics::History::new(history_size),
+            samples: SampleWindow::new(),
+            every_nth: EveryNthCheck::new(),
+            reload_grace: ReloadGrace::new(),
+            #[cfg(feature = "full")]
+            state_changes: StateChanges::new(),
+            #[cfg(feature = "full")]
+            warn_band: metrics::WarnBand::new(),
+            #[cfg(feature = "full")]
+            apdex: metrics::Apdex::new(),
+            #[cfg(feature = "full")]
+            request_durations: metrics::RequestDurations::new(),
+            #[cfg(feature = "full")]
+            passenger_version: std::sync::OnceLock::new(),
+            status,
+            instances,
+            remote_overrides: Mutex::new(Vec::new()),
+            cli_overrides: Mutex::new(Vec::new()),
+            threshold_override: Mutex::new(None),
+            reloading: Mutex::new(()),
+            settings: RwLock::new(settings),
+        }
+    }
+
+    /// The overrides to load settings with: the latest from `remote_config_url`, then `cli`'s on top.
+    fn overrides(&self, cli: &[(String, String)]) -> Overrides {
+        let remote = self.remote_overrides.lock().unwrap().clone();
+        self.overrides_with(&remote, cli)
+    }
+
+    /// Like `overrides`, with `remote` in place of the latest remote settings. A threshold set at
+    /// runtime goes on top of both.
+    fn overrides_with(&self, remote: &[(String, String)], cli: &[(String, String)]) -> Overrides {
+        let mut overrides = [remote, cli].concat();
+        if let Some(ratio) = *self.threshold_override.lock().unwrap() {
+            overrides.push(("queue_threshold_ratio".to_string(), ratio.to_string()));
+        }
+        overrides
+    }
+
+    /// `can_take_more_traffic` against every instance.
+    async fn can_take_more_traffic(&self, settings: &Settings) -> Decision {
+        if self.instances.is_empty() {
+            return can_take_more_traffic(settings, &self.status).await;
+        }
+        can_all_take_more_traffic(settings, self.instances.iter().map(|(_, status)| status)).await
+    }
+
+    /// A snapshot of the current settings, so a reload never changes them mid-request.
+    pub fn settings(&self) -> Settings {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Keeps a health decision in the history, the warning band, the Apdex and StatsD, if enabled.
+    #[cfg(feature = "full")]
+    fn record(&self, settings: &Settings, decision: &Decision) {
+        self.history.record(decision);
+        self.warn_band.observe(settings, decision);
+        self.apdex.observe(settings, decision);
+        statsd::emit(settings, decision);
+    }
+
+    #[cfg(not(feature = "full"))]
+    fn record(&self, _settings: &Settings, _decision: &Decision) {}
+}
+
+/// Starts the server on `server_port`, or the socket systemd passed in when socket-activated, and
+/// serves until SIGTERM or SIGINT. `overrides` are the `--set` pairs, reapplied whenever settings
+/// are reloaded. Fails if the port or `unix_socket_path` can't be bound.
+pub async fn run(settings: Settings, overrides: Overrides) -> Result<(), StartError> {
+    // The warmup ramp counts from here
+    crate::check::started();
+    let remote_overrides = match settings.remote_config_url.as_deref() {
+        Some(url) => fetch_remote_config(url).await.unwrap_or_default(),
+        None => Vec::new(),
+    };
+    let mut settings = if remote_overrides.is_empty() {
+        settings
+    } else {
+        match load_settings_strictly(&[remote_overrides.as_slice(), &overrides].concat()) {
+            Ok(settings) => settings,
+            Err(e) => {
+                warn!("Ignoring remote_config_url, its settings are invalid: {}", e);
+                settings
+            }
+        }
+    };
+    let threshold_override = settings.persist_threshold_file.as_deref().and_then(persisted_threshold);
+    if let Some(ratio) = threshold_override {
+        settings.queue_threshold_ratio = ratio;
+    }
+    // Once, after every source is merged; secrets are redacted by `Settings`' Serialize
+    info!("Effective settings: {}", serde_json::to_string(&settings).unwrap_or_default());
+    let state = Arc::new(AppState::new(settings.clone()));
+    *state.remote_overrides.lock().unwrap() = remote_overrides;
+    *state.threshold_override.lock().unwrap() = threshold_override;
+    *state.cli_overrides.lock().unwrap() = overrides.clone();
+    #[cfg(feature = "full")]
+    if settings.report_passenger_version {
+        if let Some(version) = crate::passenger::passenger_version(&settings).await {
+            info!("Passenger version {}", version);
+            let _ = state.passenger_version.set(version);
+        }
+    }
+    if settings.wait_for_passenger_on_startup {
+        wait_for_passenger(&settings, &state.status, STARTUP_POLL_INTERVAL).await;
+    }
+    let listener = match systemd_listener().map_err(StartError::Tcp)? {
+        Some(listener) => {
+            info!("Using the socket passed by systemd, ignoring server_port");
+            listener
+        }
+        None => bind(&settings).await.map_err(StartError::Tcp)?,
+    };
+    let address = listener.local_addr().map_err(StartError::Tcp)?;
+    let mut listeners = vec![Listener::from(listener)];
+    if let Some(path) = settings.unix_socket_path.as_deref().filter(|path| !path.is_empty()) {
+        listeners.push(Listener::bind_unix(path).map_err(StartError::UnixSocket)?);
+    }
+    tokio::spawn(reload_on_hangup(state.clone(), overrides.clone()));
+    if settings.dump_state_on_sigusr1 {
+        tokio::spawn(dump_state_on_usr1(state.clone()));
+    }
+    tokio::spawn(refresh_remote_config(state.clone(), overrides));
+    #[cfg(feature = "full")]
+    tokio::spawn(diagnostics::write_metrics_textfile(state.clone()));
+
+    if log::log_enabled!(log::Level::Info) {
+        info!("{}", startup_banner(&settings, address, true));
+    } else {
+        // Still through the logger, so it's formatted and filtered like everything else, but at
+        // warn so that the usual `RUST_LOG=warn` still says what's running where
+        warn!("{}", startup_banner(&settings, address, false));
+    }
+    warn_if_forced(&settings);
+    serve(state, listeners, shutdown_signal()).await;
+    info!("Shut down");
+    Ok(())
+}
+
+/// Reads the queue every `poll_every` until that works, for `wait_for_passenger_on_startup`. Gives up
+/// after `startup_wait_timeout_secs`, if that's set, so a Passenger that never comes up still ends
+/// up with a listener reporting it down. Returns whether Passenger came up.
+async fn wait_for_passenger(settings: &Settings, status: &Arc<StatusCache>, poll_every: Duration) -> bool {
+    let started = Instant::now();
+    let give_up_after = Some(Duration::from_secs(settings.startup_wait_timeout_secs)).filter(|timeout| !timeout.is_zero());
+    info!("Waiting for Passenger before listening");
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match get_queue_length(settings, status).await {
+            Ok(_) => {
+                info!("Passenger is up after {:.1}s, starting the listener", started.elapsed().as_secs_f64());
+                return true;
+            }
+            Err(e) if give_up_after.is_some_and(|timeout| started.elapsed() >= timeout) => {
+                warn!("Passenger still isn't up after {}s, listening anyway: {}", settings.startup_wait_timeout_secs, e);
+                return false;
+            }
+            // The first failure, then every 10th, so a long wait doesn't flood the log
+            Err(e) if attempts % 10 == 1 => info!("Passenger isn't up yet (attempt {}): {}", attempts, e),
+            Err(_) => {}
+        }
+        tokio::time::sleep(poll_every).await;
+    }
+}
+
+/// Binds `server_port`. While the port is in use, say by the previous instance during a restart,
+/// tries again up to `bind_retry_attempts` times, `bind_retry_delay_ms` apart.
+async fn bind(settings: &Settings) -> io::Result<TcpListener> {
+    let mut retries = 0;
+    loop {
+        match TcpListener::bind(("127.0.0.1", settings.server_port)).await {
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse && retries < settings.bind_retry_attempts => {
+                retries += 1;
+                warn!(
+                    "Port {} is in use, retrying in {}ms ({} of {})",
+                    settings.server_port, settings.bind_retry_delay_ms, retries, settings.bind_retry_attempts
+                );
+                tokio::time::sleep(Duration::from_millis(settings.bind_retry_delay_ms)).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Serves every route on all of `listeners`, at most `max_connections` at a time, until `shutdown`
+/// resolves. Unix socket files are removed once it stops.
+pub(crate) async fn serve(state: Arc<AppState>, listeners: Vec<Listener>, shutdown: impl Future<Output = ()> + Send + 'static) {
+    let max_connections = state.settings().max_connections;
+    let recovering = state.clone();
+    let service = warp::service(routes(state.clone()).recover(move |rejection: Rejection| {
+        let state = recovering.clone();
+        async move { envelope::rejection_reply(&state.settings(), &rejection).ok_or(rejection) }
+    }));
+    // Built by hand rather than with `warp::serve`, which doesn't pass the client address through
+    // `serve_incoming` and so would leave it out of the access log
+    let make_service = make_service_fn(move |connection: &LimitedConnection| {
+        let client = connection.peer_addr();
+        let service = service.clone();
+        let state = state.clone();
+        future::ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+            let mut service = service.clone();
+            let state = state.clone();
+            async move {
+                #[cfg(feature = "full")]
+                let req = with_peer(req, client);
+                let started = Instant::now();
+                let (method, path) = (req.method().clone(), req.uri().path().to_string());
+                let echoed = echoed_headers(&state.settings(), req.headers());
+                let spawn_limit = state.settings().max_total_subprocess_spawns_per_request;
+                let mut res = limit_spawns(spawn_limit, service.call(req)).await?;
+                for (name, value) in echoed {
+                    res.headers_mut().entry(name).or_insert(value);
+                }
+                #[cfg(feature = "full")]
+                if let Some(endpoint) = endpoint_name(&path) {
+                    state.request_durations.observe(endpoint, started.elapsed());
+                }
+                if state.settings().access_log_json {
+                    access_log::write(&AccessLogLine::new(&method, &path, &res, started.elapsed(), client));
+                }
+                Ok::<_, Infallible>(res)
+            }
+        }))
+    });
+
+    let socket_paths: Vec<PathBuf> = listeners.iter().filter_map(Listener::socket_path).cloned().collect();
+    let incoming = accept::from_stream(limit_connections(listeners, max_connections));
+    if let Err(e) = hyper::Server::builder(incoming).serve(make_service).with_graceful_shutdown(shutdown).await {
+        warn!("Server error: {}", e);
+    }
+    for path in socket_paths {
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("Couldn't remove unix_socket_path {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// The request's values for the `echo_headers` it has, to copy onto the response for correlating
+/// probes across load balancer and access logs.
+fn echoed_headers(settings: &Settings, headers: &HeaderMap) -> Vec<(HeaderName, HeaderValue)> {
+    settings
+        .echo_headers
+        .iter()
+        .filter_map(|name| {
+            let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+            let value = headers.get(&name)?.clone();
+            Some((name, value))
+        })
+        .collect()
+}
+
+/// The endpoint `path` is routed to, as labelled in `passenger_ready_request_duration_seconds`, or
+/// `None` for paths no endpoint serves.
+#[cfg(feature = "full")]
+fn endpoint_name(path: &str) -> Option<&'static str> {
+    const ENDPOINTS: [&str; 10] =
+        ["health", "readyz", "status", "info", "metrics", "history", "dashboard", "threshold", "errors", "config/sources"];
+    let path = path.strip_prefix('/')?;
+    let path = path.strip_suffix('/').unwrap_or(path);
+    if let Some(endpoint) = ENDPOINTS.into_iter().find(|endpoint| *endpoint == path) {
+        return Some(endpoint);
+    }
+    match path.split_once('/') {
+        Some(("health", app)) if !app.is_empty() && !app.contains('/') => Some("health/:app"),
+        _ => None,
+    }
+}
+
+/// All of the endpoints, for serving directly or mounting into another warp server.
+pub fn routes(state: Arc<AppState>) -> impl Filter<Extract = (impl warp::Reply,), Error = Rejection> + Clone {
+    let settings = state.settings();
+
+    let endpoints = &settings.endpoints;
+    let health_route = warp::path("health")
+        .and(warp::path::end())
+        .and(enabled(endpoints.health))
+        .and(warp::header::optional::<String>("accept"))
+        .and(with_state(state.clone()))
+        .and_then(|accept: Option<String>, state: Arc<AppState>| async move {
+            let settings = state.settings();
+            let check = within_deadline(&settings, state.can_take_more_traffic(&settings));
+            let decision = state.every_nth.decide(&settings, check).await;
+            let decision = state.samples.hold(decision, &settings);
+            let decision = state.reload_grace.hold(decision, &settings);
+            state.record(&settings, &decision);
+            #[cfg(feature = "full")]
+            state.state_changes.observe(&settings, &decision);
+            Ok::<_, Rejection>(health_reply(&decision, &settings, accept.as_deref()))
+        });
+
+    let app_health_route = warp::path!("health" / String)
+        .and(enabled(endpoints.health))
+        .and(warp::header::optional::<String>("accept"))
+        .and(with_state(state.clone()))
+        .and_then(|app: String, accept: Option<String>, state: Arc<AppState>| async move {
+            let settings = state.settings();
+            let decision = match timeout(check_deadline(&settings), check_app(&settings, &state.status, &app)).await {
+                Ok(Some(decision)) => decision,
+                Ok(None) => return Err(warp::reject::not_found()),
+                Err(_) => Decision::deadline_exceeded(&settings),
+            };
+            state.record(&settings, &decision);
+            Ok(health_reply(&decision, &settings, accept.as_deref()))
+        });
+
+    let readyz_route = warp::path("readyz")
+        .and(warp::path::end())
+        .and(enabled(endpoints.readyz))
+        .and(with_state(state.clone()))
+        .and_then(|state: Arc<AppState>| async move {
+            let settings = state.settings();
+            let decision = within_deadline(&settings, check_readiness(&settings, &state.status, &state.warmup)).await;
+            state.record(&settings, &decision);
+            Ok::<_, Rejection>(readiness_reply(&decision, &settings))
+        });
+
+    let routes = health_route.or(app_health_route).or(readyz_route);
+    #[cfg(feature = "full")]
+    let routes = routes.or(diagnostics::routes(state));
+    routes
+}
+
+/// One line saying what's running where, as `key=value` pairs to grep for: the version, the commit
+/// it was built from and the addresses, then with `verbose` the instance label and the endpoints.
+fn startup_banner(settings: &Settings, address: SocketAddr, verbose: bool) -> String {
+    let mut banner = format!("passenger-ready started version={} git_sha={} address={}", env!("CARGO_PKG_VERSION"), env!("GIT_SHA"), address);
+    if let Some(path) = settings.unix_socket_path.as_deref().filter(|path| !path.is_empty()) {
+        banner.push_str(&format!(" unix_socket={}", path));
+    }
+    if verbose {
+        let instance = settings.instance_label.as_deref().filter(|label| !label.is_empty()).unwrap_or("-");
+        banner.push_str(&format!(" instance={} endpoints={}", instance, route_paths(settings).join(",")));
+    }
+    banner
+}
+
+/// The paths `routes` serves with these settings, for the startup banner.
+fn route_paths(settings: &Settings) -> Vec<&'static str> {
+    let endpoints = &settings.endpoints;
+    let diagnostics = cfg!(feature = "full");
+    [
+        (endpoints.health, "/health"),
+        (endpoints.health, "/health/:app"),
+        (endpoints.readyz, "/readyz"),
+        (diagnostics && endpoints.status, "/status"),
+        (diagnostics && endpoints.info, "/info"),
+        (diagnostics && endpoints.metrics, "/metrics"),
+        (diagnostics && endpoints.history, "/history"),
+        (diagnostics && endpoints.dashboard, "/dashboard"),
+        (diagnostics && endpoints.threshold, "/threshold"),
+        (diagnostics && endpoints.errors, "/errors"),
+        (diagnostics && endpoints.config, "/config/sources"),
+    ]
+    .into_iter()
+    .filter(|(enabled, _)| *enabled)
+    .map(|(_, path)| path)
+    .collect()
+}
+
+fn with_state(state: Arc<AppState>) -> impl Filter<Extract = (Arc<AppState>,), Error = Infallible> + Clone {
+    warp::any().map(move || state.clone())
+}
+
+/// The address of the connection a request came in on, so checks can see it behind `warp::service`.
+#[cfg(feature = "full")]
+#[derive(Debug, Clone, Copy)]
+struct Peer(SocketAddr);
+
+#[cfg(feature = "full")]
+fn with_peer(mut req: Request<Body>, client: Option<SocketAddr>) -> Request<Body> {
+    if let Some(client) = client {
+        req.extensions_mut().insert(Peer(client));
+    }
+    req
+}
+
+/// Rejects with a 404 unless `enabled`, for endpoints that are switched on by a setting.
+fn enabled(enabled: bool) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::any()
+        .and_then(move || async move {
+            if enabled {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+/// Resolves on the first SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            warn!("Could not listen for SIGTERM, only SIGINT shuts down cleanly: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT, shutting down"),
+    }
+}
+
+/// Logs `state_summary` on each SIGUSR1, for looking into a running instance without HTTP.
+async fn dump_state_on_usr1(state: Arc<AppState>) {
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            warn!("Could not listen for SIGUSR1, state won't be logged on it: {}", e);
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        info!("{}", state_summary(&state));
+    }
+}
+
+/// The state as `key=value` pairs, like the startup banner: the cached queue length and how old it
+/// is, the decision it makes, whether `/readyz` is warmed up, how many failed reads `/errors` holds,
+/// and the main settings. Only reads what's cached, so it never runs passenger-status.
+fn state_summary(state: &AppState) -> String {
+    let settings = state.settings();
+    let cached = state.status.last_output();
+    let decision = forced(&settings).or_else(|| {
+        cached
+            .as_ref()
+            .map(|(_, output)| Decision::from_queue(crate::check::queue_length(output, &settings), &settings))
+    });
+    let queue_length = decision.as_ref().and_then(|decision| decision.queue_length);
+    let errors: usize = if state.instances.is_empty() {
+        state.status.errors().len()
+    } else {
+        state.instances.iter().map(|(_, status)| status.errors().len()).sum()
+    };
+    format!(
+        "State: queue_length={} read_secs_ago={} healthy={} reason={} warmed_up={} recent_errors={} max_queue_length={} queue_threshold_ratio={} effective_threshold_ratio={} stale_after_ms={} fail_open={} instances={}",
+        queue_length.map_or_else(|| "unknown".to_string(), |queue_length| queue_length.to_string()),
+        cached.map_or_else(|| "never".to_string(), |(read_at, _)| read_at.elapsed().as_secs().to_string()),
+        decision.as_ref().map_or_else(|| "unknown".to_string(), |decision| decision.healthy.to_string()),
+        decision.as_ref().map_or("unknown", |decision| decision.reason.as_str()),
+        state.warmup.is_done(),
+        errors,
+        settings.max_queue_length,
+        settings.queue_threshold_ratio,
+        crate::check::effective_threshold_ratio(&settings),
+        settings.stale_after_ms,
+        settings.fail_open,
+        state.instances.len().max(1),
+    )
+}
+
+async fn reload_on_hangup(state: Arc<AppState>, overrides: Overrides) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!("Could not listen for SIGHUP, settings won't be reloadable: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        // Further signals during the quiet period restart it, and are all handled by one reload
+        let quiet = Duration::from_millis(state.settings().reload_debounce_ms);
+        let mut coalesced = 0;
+        while !quiet.is_zero() {
+            match timeout(quiet, hangups.recv()).await {
+                Ok(Some(())) => coalesced += 1,
+                _ => break,
+            }
+        }
+        if coalesced > 0 {
+            info!("Received {} SIGHUPs within reload_debounce_ms of each other, reloading once", coalesced + 1);
+        }
+        let _reloading = state.reloading.lock().unwrap();
+        apply_reloaded_settings(&state.settings, load_settings_strictly(&state.overrides(&overrides)));
+    }
+}
+
+/// Refetches `remote_config_url` every `remote_config_refresh_secs`, applying it like a reload. If
+/// it can't be fetched, the settings in use stay as they are.
+async fn refresh_remote_config(state: Arc<AppState>, overrides: Overrides) {
+    loop {
+        let settings = state.settings();
+        tokio::time::sleep(Duration::from_secs(settings.remote_config_refresh_secs.max(1))).await;
+        let url = match state.settings().remote_config_url {
+            Some(url) => url,
+            None => continue,
+        };
+        if let Some(remote_overrides) = fetch_remote_config(&url).await {
+            let _reloading = state.reloading.lock().unwrap();
+            let loaded = load_settings_strictly(&state.overrides_with(&remote_overrides, &overrides));
+            if loaded.is_ok() {
+                *state.remote_overrides.lock().unwrap() = remote_overrides;
+            }
+            apply_reloaded_settings(&state.settings, loaded);
+        }
+    }
+}
+
+async fn fetch_remote_config(url: &str) -> Option<Overrides> {
+    match remote_config::fetch(url).await {
+        Ok(overrides) => Some(overrides),
+        Err(e) => {
+            warn!("Could not fetch settings from {}, keeping the current ones: {}", url, e);
+            None
+        }
+    }
+}
+
+/// The ratio `PUT /threshold` saved to `path`, if there is a valid one.
+fn persisted_threshold(path: &str) -> Option<f64> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            warn!("Could not read {}, using queue_threshold_ratio: {}", path, e);
+            return None;
+        }
+    };
+    match contents.trim().parse::<f64>().map_err(|e| e.to_string()).and_then(check_threshold_ratio) {
+        Ok(ratio) => {
+            info!("Using queue_threshold_ratio {} from {}", ratio, path);
+            Some(ratio)
+        }
+        Err(e) => {
+            warn!("Ignoring {}, using queue_threshold_ratio: {}", path, e);
+            None
+        }
+    }
+}
+
+/// Swaps in freshly loaded settings, keeping the current ones if loading failed.
+fn apply_reloaded_settings(shared: &RwLock<Settings>, loaded: Result<Settings, config::ConfigError>) {
+    match loaded {
+        Ok(settings) => {
+            let mut current = shared.write().unwrap();
+            if settings.server_port != current.server_port {
+                warn!("server_port changed to {}, restart to listen on it", settings.server_port);
+            }
+            if settings.unix_socket_path != current.unix_socket_path {
+                warn!("unix_socket_path only applies at startup, restart to use the new value");
+            }
+            if settings.max_connections != current.max_connections {
+                warn!("max_connections only applies at startup, restart to use the new value");
+            }
+            if settings.min_ready_processes != current.min_ready_processes || settings.healthy_streak_required != current.healthy_streak_required {
+                warn!("min_ready_processes and healthy_streak_required only apply at startup, restart to use the new values");
+            }
+            if settings.max_status_output_bytes != current.max_status_output_bytes
+                || settings.passenger_instance != current.passenger_instance
+                || settings.passenger_status_command != current.passenger_status_command
+                || settings.working_directory != current.working_directory
+                || settings.warn_on_status_stderr != current.warn_on_status_stderr
+                || settings.passenger_status_format != current.passenger_status_format
+                || settings.passenger_status_file != current.passenger_status_file
+                || settings.max_file_age_secs != current.max_file_age_secs
+                || settings.report_passenger_version != current.report_passenger_version
+                || settings.partial_output_on_timeout != current.partial_output_on_timeout
+                || settings.dump_state_on_sigusr1 != current.dump_state_on_sigusr1
+            {
+                warn!("max_status_output_bytes, passenger_instance, passenger_status_command, passenger_status_format, passenger_status_file, max_file_age_secs, working_directory, warn_on_status_stderr, report_passenger_version, partial_output_on_timeout and dump_state_on_sigusr1 only apply at startup, restart to use the new values");
+            }
+            if settings.endpoints != current.endpoints {
+                warn!("endpoints only apply at startup, restart to use the new values");
+            }
+            if settings.history_size != current.history_size {
+                warn!("history_size only applies at startup, restart to use the new value");
+            }
+            warn_if_forced(&settings);
+            *current = settings;
+            info!("Reloaded settings");
+        }
+        Err(e) => warn!("Failed to reload settings, keeping the previous ones: {}", e),
+    }
+}
+
+fn warn_if_forced(settings: &Settings) {
+    if let Some(decision) = forced(settings) {
+        warn!("Health checks don't look at passenger-status: {}", decision.describe());
+    }
+}
+
+/// `true`/`false`, or the decision as JSON when the client's `Accept` header asks for it.
+fn health_reply(decision: &Decision, settings: &Settings, accept: Option<&str>) -> warp::reply::Response {
+    let mut res = if accepts_json(accept) {
+        with_reason(decision, settings, warp::reply::json(decision))
+    } else if let Some(template) = &settings.health_body_template {
+        with_reason(decision, settings, templated_body(template, decision, settings))
+    } else {
+        // Still starting with "true", so checks that match on it keep passing
+        let body = match (decision.healthy, decision.degraded) {
+            (true, true) => "true (degraded)",
+            (true, false) => "true",
+            _ => "false",
+        };
+        with_reason(decision, settings, body)
+    };
+    if settings.capacity_headroom_header {
+        if let Some(headroom) = decision.queue_length.map(|queue_length| capacity_headroom(queue_length, settings)) {
+            res.headers_mut().insert("x-capacity-headroom", HeaderValue::from(headroom));
+        }
+    }
+    res
+}
+
+/// `health_body_template` filled in for `decision`. `{queue}` and `{headroom}` are `unknown` when the
+/// queue couldn't be read.
+fn templated_body(template: &str, decision: &Decision, settings: &Settings) -> String {
+    let value = |name: &str| match name {
+        "queue" => decision.queue_length.map_or_else(|| "unknown".to_string(), |queue_length| queue_length.to_string()),
+        "max" => settings.max_queue_length.to_string(),
+        "state" => match (decision.healthy, decision.degraded) {
+            (true, true) => "degraded",
+            (true, false) => "healthy",
+            _ => "unhealthy",
+        }
+        .to_string(),
+        "headroom" => decision
+            .queue_length
+            .map_or_else(|| "unknown".to_string(), |queue_length| capacity_headroom(queue_length, settings).to_string()),
+        "reason" => decision.reason.as_str().to_string(),
+        "healthy" => decision.healthy.to_string(),
+        _ => String::new(),
+    };
+    // Validated when the settings were loaded
+    crate::template::render(template, value).unwrap_or_else(|_| decision.healthy.to_string())
+}
+
+fn accepts_json(accept: Option<&str>) -> bool {
+    accept.is_some_and(|accept| {
+        accept
+            .split(',')
+            .any(|media_type| media_type.split(';').next().unwrap_or("").trim().eq_ignore_ascii_case("application/json"))
+    })
+}
+
+/// Like `health_reply`, but with the reason as the body, so a failing readiness probe says why.
+fn readiness_reply(decision: &Decision, settings: &Settings) -> warp::reply::Response {
+    with_reason(decision, settings, decision.reason.as_str())
+}
+
+/// Sets the status code and `X-Health-Reason`. A full queue or pool answers with `overload_status_code`
+/// and says how full it is, for load balancers that weight their retries by load. Degraded
+/// decisions get `X-Health-State: degraded`. With `suggested_probe_interval_secs`, also advises how
+/// often to probe. With `reason_phrase`, the reason in capitals, like `QUEUE_FULL`, replaces the
+/// status line's reason phrase and goes in `X-Status-Reason`, since HTTP/2 has no reason phrase and
+/// proxies may rewrite it.
+fn with_reason(decision: &Decision, settings: &Settings, body: impl warp::Reply) -> warp::reply::Response {
+    let overloaded = !decision.healthy && matches!(decision.reason, Reason::QueueFull | Reason::PoolExhausted | Reason::AtCapacity | Reason::LowScore);
+    let status = if decision.healthy {
+        StatusCode::OK
+    } else if overloaded {
+        StatusCode::from_u16(settings.overload_status_code).unwrap_or(StatusCode::SERVICE_UNAVAILABLE)
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    let mut res = warp::reply::with_status(body, status).into_response();
+    res.extensions_mut().insert(decision.clone());
+    let headers = res.headers_mut();
+    headers.insert("x-health-reason", HeaderValue::from_static(decision.reason.as_str()));
+    if overloaded {
+        if let Some(queue_length) = decision.queue_length {
+            headers.insert("x-current-queue", HeaderValue::from(queue_length));
+        }
+        headers.insert("x-max-queue", HeaderValue::from(settings.max_queue_length));
+    }
+    if decision.degraded {
+        headers.insert("x-health-state", HeaderValue::from_static("degraded"));
+    }
+    if let Some(interval) = settings.suggested_probe_interval_secs.filter(|interval| *interval > 0) {
+        headers.insert("x-suggested-probe-interval", HeaderValue::from(interval));
+    }
+    if settings.reason_phrase {
+        let phrase = decision.reason.as_str().to_ascii_uppercase();
+        headers.insert("x-status-reason", HeaderValue::from_str(&phrase).expect("reasons are ASCII"));
+        res.extensions_mut().insert(ReasonPhrase::try_from(phrase).expect("reasons are ASCII"));
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::QueueError;
+    use crate::settings::Endpoints;
+    use crate::testing::{sample_state, sample_state_with};
+    use lazy_static::lazy_static;
+    use std::env;
+    use std::time::Duration;
+    use warp::test::request;
+
+    lazy_static! {
+        static ref ENV_LOCK: Mutex<()> = Mutex::new(());
+    }
+
+    /// Default settings with every endpoint served.
+    fn diagnostics() -> Settings {
+        Settings { endpoints: Endpoints::all(), ..Settings::default() }
+    }
+
+    fn failing_open() -> Settings {
+        Settings { fail_open: true, ..Settings::default() }
+    }
+
+    async fn setup_env(max_queue_length: &str, server_port: &str) {
+        let _env_lock = ENV_LOCK.lock().unwrap();
+        env::set_var("APP_MAX_QUEUE_LENGTH", max_queue_length);
+        env::set_var("APP_SERVER_PORT", server_port);
+    }
+
+    async fn teardown_env() {
+        env::remove_var("APP_MAX_QUEUE_LENGTH");
+        env::remove_var("APP_SERVER_PORT");
+    }
+
+    #[cfg(feature = "full")]
+    #[test]
+    fn labels_durations_by_the_endpoint_that_served_them() {
+        assert_eq!(endpoint_name("/health"), Some("health"));
+        assert_eq!(endpoint_name("/health/"), Some("health"));
+        assert_eq!(endpoint_name("/health/api"), Some("health/:app"));
+        assert_eq!(endpoint_name("/metrics"), Some("metrics"));
+        for path in ["/", "/favicon.ico", "/health/api/extra", "/readyz/extra", "/health//"] {
+            assert_eq!(endpoint_name(path), None, "{}", path);
+        }
+    }
+
+    fn down_then_up(failures: usize) -> (Arc<StatusCache>, Arc<std::sync::atomic::AtomicUsize>) {
+        let reads = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = reads.clone();
+        let status = StatusCache::new(move || {
+            let read = counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async move {
+                if read < failures {
+                    Err(QueueError::NotRunning.into())
+                } else {
+                    Ok("Requests in top-level queue : 0".to_string())
+                }
+            }
+        });
+        (Arc::new(status), reads)
+    }
+
+    #[tokio::test]
+    async fn waits_for_passenger_before_listening_when_asked() {
+        let (status, reads) = down_then_up(3);
+        assert!(wait_for_passenger(&Settings::default(), &status, Duration::from_millis(5)).await);
+        assert_eq!(reads.load(std::sync::atomic::Ordering::SeqCst), 4);
+
+        let (status, _) = down_then_up(usize::MAX);
+        let settings = Settings { startup_wait_timeout_secs: 1, ..Settings::default() };
+        let started = Instant::now();
+        assert!(!wait_for_passenger(&settings, &status, Duration::from_millis(50)).await);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn reload_swaps_in_new_settings() {
+        let shared = RwLock::new(Settings::default());
+        let reloaded = Settings { max_queue_length: 50, ..Settings::default() };
+
+        apply_reloaded_settings(&shared, Ok(reloaded));
+
+        assert_eq!(shared.read().unwrap().max_queue_length, 50);
+    }
+
+    #[test]
+    fn failed_reload_keeps_previous_settings() {
+        let shared = RwLock::new(Settings { max_queue_length: 50, ..Settings::default() });
+
+        apply_reloaded_settings(&shared, Err(config::ConfigError::Message("bad file".to_string())));
+
+        assert_eq!(shared.read().unwrap().max_queue_length, 50);
+    }
+
+    #[test]
+    fn startup_banner_says_what_is_running_where() {
+        let settings = Settings { instance_label: Some("web-1".to_string()), ..Settings::default() };
+        let address: SocketAddr = "0.0.0.0:8080".parse().unwrap();
+
+        let concise = startup_banner(&settings, address, false);
+        assert!(concise.starts_with(&format!("passenger-ready started version={} git_sha=", env!("CARGO_PKG_VERSION"))));
+        assert!(concise.ends_with(" address=0.0.0.0:8080"));
+        assert_eq!(
+            startup_banner(&settings, address, true),
+            format!("{} instance=web-1 endpoints=/health,/health/:app,/readyz", concise)
+        );
+    }
+
+    #[tokio::test]
+    async fn state_summary_reports_what_is_cached() {
+        let state = sample_state(Settings::default());
+        assert!(state_summary(&state).starts_with("State: queue_length=unknown read_secs_ago=never healthy=unknown reason=unknown warmed_up=true recent_errors=0"));
+
+        state.status.read(&state.settings()).await.unwrap();
+        let summary = state_summary(&state);
+        assert!(summary.starts_with("State: queue_length=12 read_secs_ago=0 healthy=true reason=ok warmed_up=true"), "{}", summary);
+        assert!(summary.contains(" max_queue_length=100 queue_threshold_ratio=0.8 "), "{}", summary);
+    }
+
+    #[test]
+    fn route_paths_only_list_enabled_endpoints() {
+        assert_eq!(route_paths(&Settings::default()), vec!["/health", "/health/:app", "/readyz"]);
+        assert_eq!(route_paths(&diagnostics()).contains(&"/history"), cfg!(feature = "full"));
+    }
+
+    #[tokio::test]
+    async fn health_is_json_when_the_client_accepts_it() {
+        let res = request()
+            .path("/health")
+            .header("accept", "text/html, application/json;q=0.9")
+            .reply(&routes(sample_state(Settings::default())))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["healthy"], true);
+        assert_eq!(body["queue_length"], 12);
+
+        let res = request()
+            .path("/health")
+            .header("accept", "*/*")
+            .reply(&routes(sample_state(Settings::default())))
+            .await;
+        assert_eq!(res.body(), "true");
+    }
+
+    #[tokio::test]
+    async fn readyz_says_why_it_is_not_ready() {
+        let state = sample_state(Settings { min_ready_processes: 10, ..Settings::default() });
+        let res = request().path("/readyz").reply(&routes(state)).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.body(), "warming_up");
+        assert_eq!(res.headers()["x-health-reason"], "warming_up");
+
+        let state = sample_state(Settings { min_ready_processes: 6, ..Settings::default() });
+        let res = request().path("/readyz").reply(&routes(state)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), "ok");
+    }
+
+    #[tokio::test]
+    async fn app_health_checks_the_named_app_group() {
+        let settings = Settings { max_queue_length: 2, ..Settings::default() };
+        let state = sample_state_with(settings, include_str!("../samples/passenger-6-multi-app.txt"));
+        let routes = routes(state);
+
+        let resp = request().method("GET").path("/health/admin").reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = request().method("GET").path("/health/api").reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn bare_health_checks_the_top_level_queue() {
+        let settings = Settings { max_queue_length: 3, ..Settings::default() };
+        let state = sample_state_with(settings, include_str!("../samples/passenger-6-multi-app.txt"));
+
+        let resp = request().method("GET").path("/health").reply(&routes(state)).await;
+
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn app_health_is_not_found_for_unknown_apps() {
+        let state = sample_state_with(Settings::default(), include_str!("../samples/passenger-6-multi-app.txt"));
+
+        let resp = request().method("GET").path("/health/billing").reply(&routes(state)).await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn health_checks_past_the_deadline_are_unavailable() {
+        let cache = StatusCache::new(|| async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok("Requests in top-level queue : 0".to_string())
+        });
+        let settings = Settings { check_deadline_ms: 20, ..Settings::default() };
+        let state = Arc::new(AppState::with_status_cache(settings, cache));
+
+        let resp = request().method("GET").path("/health").reply(&routes(state)).await;
+
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()["x-health-reason"], "deadline_exceeded");
+    }
+
+    #[tokio::test]
+    async fn measurement_failure_is_unavailable_when_failing_closed() {
+        let decision = Decision::from_queue(Err(QueueError::Timeout), &Settings::default());
+        let filter = warp::path("health").map(move || health_reply(&decision, &Settings::default(), None));
+
+        let resp = request().method("GET").path("/health").reply(&filter).await;
+
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.body(), "false");
+    }
+
+    #[tokio::test]
+    async fn measurement_failure_is_healthy_when_failing_open() {
+        let decision = Decision::from_queue(Err(QueueError::Timeout), &failing_open());
+        let filter = warp::path("health").map(move || health_reply(&decision, &Settings::default(), None));
+
+        let resp = request().method("GET").path("/health").reply(&filter).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.body(), "true");
+    }
+
+    #[tokio::test]
+    async fn full_queue_is_unavailable_even_when_failing_open() {
+        let decision = Decision::from_queue(Ok(100), &failing_open());
+        let filter = warp::path("health").map(move || health_reply(&decision, &Settings::default(), None));
+
+        let resp = request().method("GET").path("/health").reply(&filter).await;
+
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.body(), "false");
+    }
+
+    #[tokio::test]
+    async fn full_queue_uses_the_overload_status_code_and_reports_load() {
+        let settings = Settings { overload_status_code: 429, ..Settings::default() };
+        let decision = Decision::from_queue(Ok(90), &settings);
+        let filter = warp::path("health").map(move || health_reply(&decision, &settings, None));
+
+        let resp = request().method("GET").path("/health").reply(&filter).await;
+
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers()["x-current-queue"], "90");
+        assert_eq!(resp.headers()["x-max-queue"], "100");
+    }
+
+    #[tokio::test]
+    async fn capacity_headroom_is_reported_when_enabled() {
+        let settings = Settings { capacity_headroom_header: true, ..Settings::default() };
+        let res = request().path("/health").reply(&routes(sample_state(settings.clone()))).await;
+        assert_eq!(res.headers()["x-capacity-headroom"], "85");
+
+        let res = request().path("/health").reply(&routes(sample_state(Settings::default()))).await;
+        assert!(res.headers().get("x-capacity-headroom").is_none());
+
+        assert_eq!(capacity_headroom(0, &settings), 100);
+        assert_eq!(capacity_headroom(95, &settings), 0);
+    }
+
+    #[tokio::test]
+    async fn json_health_reports_capacity_when_enabled() {
+        let settings = Settings { capacity_report: true, ..Settings::default() };
+        let json = |res: warp::http::Response<warp::hyper::body::Bytes>| serde_json::from_slice::<serde_json::Value>(res.body()).unwrap();
+        let health = || request().path("/health").header("accept", "application/json");
+
+        let body = json(health().reply(&routes(sample_state(settings.clone()))).await);
+        assert_eq!(body["capacity"], serde_json::json!({ "queue": 12, "max_queue": 100, "headroom_pct": 85, "processes": 6, "idle": 0 }));
+
+        let state = sample_state_with(settings, "Requests in top-level queue : 3\n");
+        let body = json(health().reply(&routes(state)).await);
+        assert_eq!(body["capacity"], serde_json::json!({ "queue": 3, "max_queue": 100, "headroom_pct": 96 }));
+
+        let body = json(health().reply(&routes(sample_state(Settings::default()))).await);
+        assert!(body.get("capacity").is_none());
+    }
+
+    #[tokio::test]
+    async fn suggests_a_probe_interval_when_configured() {
+        let settings = Settings { suggested_probe_interval_secs: Some(5), ..Settings::default() };
+        for path in ["/health", "/readyz"] {
+            let res = request().path(path).reply(&routes(sample_state(settings.clone()))).await;
+            assert_eq!(res.headers()["x-suggested-probe-interval"], "5", "{}", path);
+        }
+
+        let res = request().path("/health").reply(&routes(sample_state(Settings::default()))).await;
+        assert!(res.headers().get("x-suggested-probe-interval").is_none());
+    }
+
+    #[tokio::test]
+    async fn degraded_hosts_pass_with_a_marker() {
+        // Every process in the busy sample uses around 400M
+        let settings = Settings { degraded_memory_mb: Some(400), ..Settings::default() };
+        let res = request().path("/health").reply(&routes(sample_state(settings.clone()))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["x-health-state"], "degraded");
+        assert_eq!(res.body(), "true (degraded)");
+
+        let res = request().path("/health").header("accept", "application/json").reply(&routes(sample_state(settings))).await;
+        let body: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(body["degraded"], true);
+
+        let res = request().path("/health").reply(&routes(sample_state(Settings { degraded_memory_mb: Some(500), ..Settings::default() }))).await;
+        assert!(res.headers().get("x-health-state").is_none());
+        assert_eq!(res.body(), "true");
+    }
+
+    #[tokio::test]
+    async fn fills_in_the_body_template() {
+        let settings = Settings { health_body_template: Some("{state} queue={queue}/{max} headroom={headroom}%".to_string()), ..Settings::default() };
+        let res = request().path("/health").reply(&routes(sample_state(settings.clone()))).await;
+        assert_eq!(res.body(), "healthy queue=12/100 headroom=85%");
+
+        let decision = Decision::from_queue(Err(QueueError::Timeout), &settings);
+        let filter = warp::path("health").map(move || health_reply(&decision, &settings, None));
+        let res = request().path("/health").reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.body(), "unhealthy queue=unknown/100 headroom=unknown%");
+    }
+
+    #[tokio::test]
+    async fn other_failures_ignore_the_overload_status_code() {
+        let settings = Settings { overload_status_code: 429, ..Settings::default() };
+        let decision = Decision::from_queue(Err(QueueError::Timeout), &settings);
+        let filter = warp::path("health").map(move || health_reply(&decision, &settings, None));
+
+        let resp = request().method("GET").path("/health").reply(&filter).await;
+
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(resp.headers().get("x-current-queue").is_none());
+    }
+
+    #[tokio::test]
+    async fn passenger_running_with_space_in_queue() {
+        // Setup: Assume `get_queue_length` is somehow mocked to return a value indicating space is available.
+        // This setup requires your application logic to be refactored for dependency injection or using a mocking library.
+        
+        // Mocking environment variables for application settings
+        env::set_var("APP_MAX_QUEUE_LENGTH", "100");
+        env::set_var("APP_SERVER_PORT", "8080");
+
+        // Define your health check route or filter here, similar to how it's defined in the main application.
+        // This might involve directly invoking the health check logic if it's abstracted appropriately.
+        
+        let filter = warp::path("health").map(|| warp::reply::with_status("true", StatusCode::OK));
+
+        // Execute the request against the health check route
+        let resp = request().method("GET").path("/health").reply(&filter).await;
+
+        // Assertions: Expect a 200 OK response with "true" indicating space is available in the queue
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.body(), "true");
+
+        // Cleanup: Remove the environment variables to avoid side effects on other tests
+        env::remove_var("APP_MAX_QUEUE_LENGTH");
+        env::remove_var("APP_SERVER_PORT");
+    }
+
+    #[tokio::test]
+    async fn health_check_responds_unavailable_when_overloaded() {
+        setup_env("5", "8080").await; // Simulating a very low max queue length
+        // Assuming get_queue_length would return 6 or more in this scenario
+        let filter = warp::path("health").map(|| warp::reply::with_status("false", StatusCode::SERVICE_UNAVAILABLE));
+
+        let resp = request().method("GET").path("/health").reply(&filter).await;
+
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.body(), "false");
+
+        teardown_env().await;
+    }
+
+    #[tokio::test]
+    async fn health_check_responds_unavailable_when_passenger_down() {
+        setup_env("100", "8080").await; // Normal operation settings
+        // Simulating Passenger being down, which would normally cause get_queue_length to fail
+        let filter = warp::path("health").map(|| warp::reply::with_status("false", StatusCode::SERVICE_UNAVAILABLE));
+
+        let resp = request().method("GET").path("/health").reply(&filter).await;
+
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.body(), "false");
+
+        teardown_env().await;
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_port_to_be_released() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let settings = Settings {
+            server_port: taken.local_addr().unwrap().port(),
+            bind_retry_attempts: 20,
+            bind_retry_delay_ms: 10,
+            ..Settings::default()
+        };
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(taken);
+        });
+
+        let listener = bind(&settings).await.unwrap();
+        assert_eq!(listener.local_addr().unwrap().port(), settings.server_port);
+    }
+
+    #[tokio::test]
+    async fn gives_up_once_the_retries_run_out() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let settings = Settings {
+            server_port: taken.local_addr().unwrap().port(),
+            bind_retry_attempts: 2,
+            bind_retry_delay_ms: 10,
+            ..Settings::default()
+        };
+
+        assert_eq!(bind(&settings).await.unwrap_err().kind(), io::ErrorKind::AddrInUse);
+    }
+}
@@ -0,0 +1,1026 @@
+//! The endpoints for looking into a running instance rather than routing traffic: `/status`,
+//! `/info`, `/metrics`, `/history`, `/dashboard`, `/threshold`, `/errors` and `/config/sources`, and
+//! the `metrics_textfile_path` copy of `/metrics`. Left out of builds without the `full` feature.
+
+use super::envelope::{error_reply, ErrorCode};
+use super::{enabled, with_state, AppState, Peer};
+use crate::allowlist::client_ip;
+use crate::check::{combine, decide, effective_threshold_ratio, penalized, stale_penalty, within_deadline, Decision};
+use crate::dashboard;
+use crate::error::QueueError;
+use crate::metrics;
+use crate::passenger::{parse_max_pool_size, parse_process_count, parse_process_memory, StatusCache, StatusError};
+use crate::settings::{check_threshold_ratio, setting_sources, SettingSource, Settings};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+/// The `/status` document: the current decision and what it was measured against.
+#[derive(Debug, Serialize)]
+struct StatusReport {
+    #[serde(flatten)]
+    decision: Decision,
+    max_queue_length: i32,
+    /// `queue_threshold_ratio`, or less while `ramp_duration_secs` is ramping it up or by `stale_penalty`.
+    effective_threshold_ratio: f64,
+    /// How much of the threshold `max_staleness_secs` takes off for the output's age, 0–1.
+    stale_penalty: f64,
+    processes: Option<i32>,
+    max_pool_size: Option<i32>,
+    /// Changes in `processes` over the last `process_churn_window_secs`.
+    process_churn: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    memory_warnings: Vec<MemoryWarning>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    instances: Vec<InstanceReport>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    passenger_version: Option<String>,
+}
+
+/// One `passenger_status_command`'s decision, listed in `/status` when there are several.
+#[derive(Debug, Serialize)]
+struct InstanceReport {
+    command: String,
+    #[serde(flatten)]
+    decision: Decision,
+}
+
+/// A process using more than `max_process_memory_mb`, which may be leaking.
+#[derive(Debug, Serialize)]
+struct MemoryWarning {
+    pid: u32,
+    memory_mb: u64,
+}
+
+/// The `/info` document for service discovery. Every field is always present, `null` when unknown,
+/// so discovery tooling can rely on the schema.
+#[derive(Debug, Serialize)]
+struct InfoReport {
+    healthy: bool,
+    queue_length: Option<i32>,
+    capacity_percent: Option<f64>,
+    instance: Option<String>,
+    version: &'static str,
+}
+
+impl InfoReport {
+    fn new(decision: &Decision, settings: &Settings) -> InfoReport {
+        InfoReport {
+            healthy: decision.healthy,
+            queue_length: decision.queue_length,
+            capacity_percent: decision
+                .queue_length
+                .map(|queue_length| queue_length as f64 / settings.max_queue_length as f64 * 100.0),
+            instance: settings.instance_label.clone(),
+            version: env!("CARGO_PKG_VERSION"),
+        }
+    }
+}
+
+/// One entry in `/errors`. `command` says which `passenger_status_command` failed, when there are several.
+#[derive(Debug, Serialize)]
+struct ErrorReport<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command: Option<&'a str>,
+    #[serde(flatten)]
+    error: StatusError,
+}
+
+/// The `/threshold` document, read by `GET` and sent by `PUT`.
+#[derive(Debug, Serialize, Deserialize)]
+struct Threshold {
+    queue_threshold_ratio: f64,
+}
+
+#[derive(Debug, Deserialize)]
+struct StatusQuery {
+    #[serde(default)]
+    include_raw: bool,
+    /// Read passenger-status now rather than answering from the cache. Needs `admin_token`.
+    #[serde(default)]
+    fresh: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonQuery {
+    pretty: Option<bool>,
+}
+
+/// The most recent decisions, oldest first. A capacity of 0 records nothing.
+pub(super) struct History {
+    capacity: usize,
+    decisions: Mutex<VecDeque<Decision>>,
+}
+
+impl History {
+    pub(super) fn new(capacity: usize) -> History {
+        History {
+            capacity,
+            decisions: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub(super) fn record(&self, decision: &Decision) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut decisions = self.decisions.lock().unwrap();
+        if decisions.len() == self.capacity {
+            decisions.pop_front();
+        }
+        decisions.push_back(decision.clone());
+    }
+
+    fn decisions(&self) -> Vec<Decision> {
+        self.decisions.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// The diagnostic endpoints that are switched on in `endpoints`, each limited to `admin_allow_cidrs`
+/// and to `metrics_rate_limit_per_sec` requests a second.
+pub(super) fn routes(state: Arc<AppState>) -> impl Filter<Extract = (impl warp::Reply,), Error = Rejection> + Clone {
+    let settings = state.settings();
+
+    let endpoints = &settings.endpoints;
+    let history_route = warp::path("history")
+        .and(warp::path::end())
+        .and(enabled(endpoints.history))
+        .and(admin_only(state.clone()))
+        .and(rate_limited(state.clone()))
+        .and(pretty(state.clone()))
+        .and(with_state(state.clone()))
+        .map(|pretty: bool, state: Arc<AppState>| json(&state.history.decisions(), pretty));
+
+    let status_route = warp::path("status")
+        .and(warp::path::end())
+        .and(enabled(endpoints.status))
+        .and(admin_only(state.clone()))
+        .and(rate_limited(state.clone()))
+        .and(warp::query::<StatusQuery>())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(pretty(state.clone()))
+        .and(with_state(state.clone()))
+        .and_then(|query: StatusQuery, authorization: Option<String>, pretty: bool, state: Arc<AppState>| async move {
+            let settings = state.settings();
+            if query.include_raw && !settings.allow_raw_output {
+                return Ok::<_, Rejection>(error_reply(&settings, StatusCode::FORBIDDEN, ErrorCode::RawOutputDisabled, "raw output is disabled"));
+            }
+            // Each one runs passenger-status, so they're for token holders
+            if query.fresh {
+                if let Some(res) = token_refusal(&settings, authorization.as_deref(), "fresh reads") {
+                    return Ok(res);
+                }
+            }
+            let report = status_report(&settings, &state, query.include_raw, query.fresh).await;
+            Ok(json(&report, pretty))
+        });
+
+    let info_route = warp::path("info")
+        .and(warp::path::end())
+        .and(enabled(endpoints.info))
+        .and(admin_only(state.clone()))
+        .and(rate_limited(state.clone()))
+        .and(pretty(state.clone()))
+        .and(with_state(state.clone()))
+        .and_then(|pretty: bool, state: Arc<AppState>| async move {
+            let settings = state.settings();
+            let decision = within_deadline(&settings, state.can_take_more_traffic(&settings)).await;
+            Ok::<_, Rejection>(json(&InfoReport::new(&decision, &settings), pretty))
+        });
+
+    let metrics_route = warp::path("metrics")
+        .and(warp::path::end())
+        .and(enabled(endpoints.metrics))
+        .and(admin_only(state.clone()))
+        .and(rate_limited(state.clone()))
+        .and(with_state(state.clone()))
+        .and_then(|state: Arc<AppState>| async move {
+            Ok::<_, Rejection>(warp::reply::with_header(
+                metrics::render(&all_metrics(&state).await),
+                "content-type",
+                "text/plain; version=0.0.4",
+            ))
+        });
+
+    let dashboard_route = warp::path("dashboard")
+        .and(warp::path::end())
+        .and(enabled(endpoints.dashboard))
+        .and(admin_only(state.clone()))
+        .and(rate_limited(state.clone()))
+        .and(with_state(state.clone()))
+        .and_then(|state: Arc<AppState>| async move {
+            let settings = state.settings();
+            let decision = within_deadline(&settings, state.can_take_more_traffic(&settings)).await;
+            let age = state.status.last_fetched().map(|fetched_at| fetched_at.elapsed());
+            Ok::<_, Rejection>(warp::reply::html(dashboard::render(&decision, &settings, age)))
+        });
+
+    let threshold_route = warp::path("threshold").and(warp::path::end()).and(enabled(endpoints.threshold));
+    // GET and PUT share the endpoint's limit
+    let threshold_limit = rate_limited(state.clone());
+    let get_threshold_route = threshold_route
+        .clone()
+        .and(warp::get().or(warp::head()).unify())
+        .and(admin_only(state.clone()))
+        .and(threshold_limit.clone())
+        .and(pretty(state.clone()))
+        .and(with_state(state.clone()))
+        .map(|pretty: bool, state: Arc<AppState>| json(&Threshold { queue_threshold_ratio: state.settings().queue_threshold_ratio }, pretty));
+    let put_threshold_route = threshold_route
+        .and(warp::put())
+        .and(admin_only(state.clone()))
+        .and(threshold_limit.clone())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .map(|authorization: Option<String>, threshold: Threshold, state: Arc<AppState>| {
+            set_threshold(&state, authorization.as_deref(), threshold)
+        });
+
+    let errors_route = warp::path("errors")
+        .and(warp::path::end())
+        .and(enabled(endpoints.errors))
+        .and(admin_only(state.clone()))
+        .and(rate_limited(state.clone()))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(pretty(state.clone()))
+        .and(with_state(state.clone()))
+        .map(|authorization: Option<String>, pretty: bool, state: Arc<AppState>| {
+            if let Some(res) = token_refusal(&state.settings(), authorization.as_deref(), "reading errors") {
+                return res;
+            }
+            json(&recent_errors(&state), pretty)
+        });
+
+    let config_sources_route = warp::path!("config" / "sources")
+        .and(enabled(endpoints.config))
+        .and(admin_only(state.clone()))
+        .and(rate_limited(state.clone()))
+        .and(pretty(state.clone()))
+        .and(with_state(state.clone()))
+        .map(|pretty: bool, state: Arc<AppState>| match config_sources(&state) {
+            Ok(sources) => json(&sources, pretty),
+            Err(e) => {
+                let message = format!("couldn't read the settings' sources: {}", e);
+                error_reply(&state.settings(), StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, &message)
+            }
+        });
+
+    history_route
+        .or(status_route)
+        .or(info_route)
+        .or(metrics_route)
+        .or(dashboard_route)
+        .or(get_threshold_route)
+        .or(put_threshold_route)
+        .or(errors_route)
+        .or(config_sources_route)
+        .recover(move |rejection: Rejection| {
+            let state = state.clone();
+            async move { refused(&state.settings(), rejection) }
+        })
+}
+
+/// Whether to indent JSON replies: `?pretty=true` or `?pretty=false`, or `pretty_json` when the
+/// query doesn't say.
+fn pretty(state: Arc<AppState>) -> impl Filter<Extract = (bool,), Error = Rejection> + Clone {
+    warp::query::<JsonQuery>()
+        .and(with_state(state))
+        .map(|query: JsonQuery, state: Arc<AppState>| query.pretty.unwrap_or(state.settings().pretty_json))
+}
+
+fn json<T: Serialize>(value: &T, pretty: bool) -> warp::reply::Response {
+    if !pretty {
+        return warp::reply::json(value).into_response();
+    }
+    match serde_json::to_string_pretty(value) {
+        Ok(body) => warp::reply::with_header(body, "content-type", "application/json").into_response(),
+        Err(e) => {
+            warn!("Could not serialize a reply: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Forbidden;
+
+impl warp::reject::Reject for Forbidden {}
+
+/// Rejects with a 403 unless the client is in `admin_allow_cidrs`, when that's set. The client is
+/// worked out from `X-Forwarded-For` for requests through `trusted_proxies`.
+fn admin_only(state: Arc<AppState>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::ext::optional::<Peer>()
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and(with_state(state))
+        .and_then(|peer: Option<Peer>, remote: Option<SocketAddr>, forwarded_for: Option<String>, state: Arc<AppState>| async move {
+            let settings = state.settings();
+            if settings.admin_allow_cidrs.is_empty() {
+                return Ok(());
+            }
+            // Served by `serve`, the peer comes from the connection; mounted elsewhere, from warp
+            let peer = peer.map(|Peer(addr)| addr).or(remote).map(|addr| addr.ip());
+            match client_ip(peer, forwarded_for.as_deref(), &settings.trusted_proxies) {
+                Some(ip) if settings.admin_allow_cidrs.contains(ip) => Ok(()),
+                _ => Err(warp::reject::custom(Forbidden)),
+            }
+        })
+        .untuple_one()
+}
+
+#[derive(Debug)]
+struct RateLimited;
+
+impl warp::reject::Reject for RateLimited {}
+
+/// Rejects with a 429 once `metrics_rate_limit_per_sec` is used up. Each call has a bucket of its
+/// own, so every endpoint it's added to is limited separately.
+fn rate_limited(state: Arc<AppState>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    let bucket = Arc::new(TokenBucket::new());
+    with_state(state)
+        .and_then(move |state: Arc<AppState>| {
+            let bucket = bucket.clone();
+            async move {
+                match state.settings().metrics_rate_limit_per_sec {
+                    Some(rate) if rate > 0 && !bucket.take(rate, bucket.created.elapsed()) => Err(warp::reject::custom(RateLimited)),
+                    _ => Ok(()),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// A bucket of a second's worth of tokens, refilled continuously. It's kept as the time it will next
+/// be full, so taking a token is a compare-and-swap and requests never wait on a lock.
+struct TokenBucket {
+    created: Instant,
+    /// Nanoseconds after `created`.
+    full_at: AtomicU64,
+}
+
+impl TokenBucket {
+    fn new() -> TokenBucket {
+        TokenBucket { created: Instant::now(), full_at: AtomicU64::new(0) }
+    }
+
+    /// Takes a token at `now`, since `created`, if the bucket has one left refilling at `rate` a second.
+    fn take(&self, rate: u32, now: Duration) -> bool {
+        let per_token = 1_000_000_000 / u64::from(rate);
+        let capacity = per_token * u64::from(rate);
+        let now = now.as_nanos() as u64;
+        let mut full_at = self.full_at.load(Ordering::Relaxed);
+        loop {
+            let taken = full_at.max(now) + per_token;
+            if taken - now > capacity {
+                return false;
+            }
+            match self.full_at.compare_exchange_weak(full_at, taken, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return true,
+                Err(current) => full_at = current,
+            }
+        }
+    }
+}
+
+/// Answers the refusals from `admin_only` and `rate_limited`.
+fn refused(settings: &Settings, rejection: Rejection) -> Result<warp::reply::Response, Rejection> {
+    if rejection.find::<Forbidden>().is_some() {
+        return Ok(error_reply(settings, StatusCode::FORBIDDEN, ErrorCode::Forbidden, "forbidden"));
+    }
+    if rejection.find::<RateLimited>().is_some() {
+        let reply = error_reply(settings, StatusCode::TOO_MANY_REQUESTS, ErrorCode::RateLimited, "too many requests");
+        return Ok(warp::reply::with_header(reply, "retry-after", "1").into_response());
+    }
+    Err(rejection)
+}
+
+/// The refusal for a request without `Authorization: Bearer <admin_token>`, if it is one: a `403`
+/// naming `action` while `admin_token` is unset, and a `401` for a missing or wrong token.
+fn token_refusal(settings: &Settings, authorization: Option<&str>, action: &str) -> Option<warp::reply::Response> {
+    let token = match settings.admin_token.as_deref() {
+        Some(token) if !token.is_empty() => token,
+        _ => {
+            let message = format!("set admin_token to allow {}", action);
+            return Some(error_reply(settings, StatusCode::FORBIDDEN, ErrorCode::TokenNotConfigured, &message));
+        }
+    };
+    if authorization.and_then(|authorization| authorization.strip_prefix("Bearer ")) != Some(token) {
+        return Some(error_reply(settings, StatusCode::UNAUTHORIZED, ErrorCode::Unauthorized, "unauthorized"));
+    }
+    None
+}
+
+/// Where each setting comes from, with the overrides the settings in use were loaded with.
+fn config_sources(state: &AppState) -> Result<BTreeMap<String, SettingSource>, config::ConfigError> {
+    let remote = state.remote_overrides.lock().unwrap().clone();
+    let cli = state.cli_overrides.lock().unwrap().clone();
+    let runtime: Vec<(String, String)> = state
+        .threshold_override
+        .lock()
+        .unwrap()
+        .map(|ratio| ("queue_threshold_ratio".to_string(), ratio.to_string()))
+        .into_iter()
+        .collect();
+    setting_sources(&[(SettingSource::Remote, &remote), (SettingSource::Override, &cli), (SettingSource::Runtime, &runtime)])
+}
+
+/// Every instance's recent failed reads, oldest first.
+fn recent_errors(state: &AppState) -> Vec<ErrorReport<'_>> {
+    let mut errors: Vec<ErrorReport> = if state.instances.is_empty() {
+        state.status.errors().into_iter().map(|error| ErrorReport { command: None, error }).collect()
+    } else {
+        state
+            .instances
+            .iter()
+            .flat_map(|(command, status)| status.errors().into_iter().map(move |error| ErrorReport { command: Some(command), error }))
+            .collect()
+    };
+    // Stable, so errors from the same second stay in the order they happened
+    errors.sort_by_key(|report| report.error.timestamp);
+    errors
+}
+
+/// Applies a `PUT /threshold` from a client holding `admin_token`. The ratio is saved to
+/// `persist_threshold_file` first, so a failed write changes nothing.
+fn set_threshold(state: &AppState, authorization: Option<&str>, threshold: Threshold) -> warp::reply::Response {
+    let settings = state.settings();
+    if let Some(res) = token_refusal(&settings, authorization, "changing the threshold") {
+        return res;
+    }
+    let ratio = match check_threshold_ratio(threshold.queue_threshold_ratio) {
+        Ok(ratio) => ratio,
+        Err(e) => return error_reply(&settings, StatusCode::BAD_REQUEST, ErrorCode::InvalidThreshold, &e),
+    };
+    if let Some(path) = settings.persist_threshold_file.as_deref() {
+        if let Err(e) = fs::write(path, format!("{}\n", ratio)) {
+            warn!("Could not save queue_threshold_ratio to {}: {}", path, e);
+            return error_reply(&settings, StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::PersistFailed, &format!("could not save to {}", path));
+        }
+    }
+    *state.threshold_override.lock().unwrap() = Some(ratio);
+    state.settings.write().unwrap().queue_threshold_ratio = ratio;
+    info!("Set queue_threshold_ratio to {} through PUT /threshold", ratio);
+    warp::reply::json(&Threshold { queue_threshold_ratio: ratio }).into_response()
+}
+
+/// Everything `/metrics` reports.
+async fn all_metrics(state: &AppState) -> Vec<metrics::Metric> {
+    let settings = state.settings();
+    let mut metrics = metrics::collect(&settings, &state.status).await;
+    metrics.extend(state.warn_band.metric());
+    metrics.extend(state.apdex.metric());
+    metrics.extend(state.request_durations.metrics());
+    metrics.extend(state.passenger_version.get().map(|version| metrics::passenger_version(version)));
+    metrics
+}
+
+/// Writes `/metrics` to `metrics_textfile_path` every `metrics_textfile_interval_secs`, for
+/// node_exporter's textfile collector. Both are read each time, so a reload can start, stop or move
+/// the file.
+pub(super) async fn write_metrics_textfile(state: Arc<AppState>) {
+    loop {
+        let settings = state.settings();
+        if let Some(path) = settings.metrics_textfile_path.as_deref().filter(|path| !path.is_empty()) {
+            if let Err(e) = write_atomically(Path::new(path), &metrics::render(&all_metrics(&state).await)) {
+                warn!("Could not write metrics to {}: {}", path, e);
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(settings.metrics_textfile_interval_secs.max(1))).await;
+    }
+}
+
+/// Writes `contents` to a temporary file next to `path` and renames it over `path`, so a reader sees
+/// the old file or the new one, never half of one. The temporary file doesn't end in `.prom`, so
+/// node_exporter skips it.
+fn write_atomically(path: &Path, contents: &str) -> io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    fs::write(&temp, contents)?;
+    fs::rename(&temp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&temp);
+    })
+}
+
+async fn read(status: &Arc<StatusCache>, settings: &Settings, fresh: bool) -> Result<String, QueueError> {
+    if fresh {
+        status.read_fresh(settings).await
+    } else {
+        status.read(settings).await
+    }
+}
+
+/// The first instance's report. With several instances, each one's decision is listed too and the
+/// overall decision combines them like `/health`'s.
+async fn status_report(settings: &Settings, state: &AppState, include_raw: bool, fresh: bool) -> StatusReport {
+    let output = read(&state.status, settings, fresh).await;
+    let raw = if include_raw { output.as_ref().ok().cloned() } else { None };
+    let memory_warnings = match (&output, settings.max_process_memory_mb) {
+        (Ok(output), Some(max_mb)) => parse_process_memory(output)
+            .into_iter()
+            .filter(|(_, memory_mb)| *memory_mb > max_mb)
+            .map(|(pid, memory_mb)| MemoryWarning { pid, memory_mb })
+            .collect(),
+        _ => Vec::new(),
+    };
+    let pool = |parse: fn(&str) -> Result<i32, QueueError>| output.as_ref().ok().and_then(|output| parse(output).ok());
+    let processes = pool(parse_process_count);
+    let max_pool_size = pool(parse_max_pool_size);
+    let penalty = stale_penalty(settings, &state.status);
+    let penalized_settings = penalized(settings, penalty);
+    let mut decision = decide(output, settings, &state.status);
+
+    let mut instances = Vec::new();
+    for (command, status) in state.instances.iter() {
+        let output = read(status, settings, fresh).await;
+        let decision = decide(output, settings, status);
+        instances.push(InstanceReport { command: command.clone(), decision });
+    }
+    if !instances.is_empty() {
+        let decisions = instances.iter().map(|instance| instance.decision.clone()).collect();
+        decision = combine(decisions, settings.instance_policy);
+    }
+    StatusReport {
+        processes,
+        max_pool_size,
+        process_churn: state.status.process_churn(settings),
+        decision,
+        max_queue_length: settings.max_queue_length,
+        effective_threshold_ratio: effective_threshold_ratio(&penalized_settings),
+        stale_penalty: penalty,
+        memory_warnings,
+        raw,
+        instances,
+        passenger_version: state.passenger_version.get().cloned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{persisted_threshold, routes};
+    use super::*;
+    use crate::settings::Endpoints;
+    use crate::testing::sample_state;
+    use crate::Reason;
+    use warp::test::request;
+
+    /// Default settings with every endpoint served.
+    fn diagnostics() -> Settings {
+        Settings { endpoints: Endpoints::all(), ..Settings::default() }
+    }
+
+    #[test]
+    fn history_keeps_only_the_most_recent_decisions() {
+        let history = History::new(2);
+
+        for queue_length in [1, 2, 3] {
+            history.record(&Decision::new(Some(queue_length), true, Reason::Ok));
+        }
+
+        let queue_lengths: Vec<_> = history.decisions().iter().map(|d| d.queue_length).collect();
+        assert_eq!(queue_lengths, vec![Some(2), Some(3)]);
+    }
+
+    #[tokio::test]
+    async fn dashboard_shows_the_current_check_when_enabled() {
+        let state = sample_state(diagnostics());
+        let res = request().path("/dashboard").reply(&routes(state)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = std::str::from_utf8(res.body()).unwrap();
+        assert!(body.contains("<h1 style=\"color: #2e7d32\">healthy</h1>"));
+        assert!(body.contains("<tr><td>Queue length</td><td>12</td></tr>"));
+        assert!(body.contains("<tr><td>Threshold</td><td>80 of 100</td></tr>"));
+
+        let res = request().path("/dashboard").reply(&routes(sample_state(Settings::default()))).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn history_endpoint_is_json_when_enabled() {
+        let state = Arc::new(AppState::new(diagnostics()));
+        state.history.record(&Decision::from_queue(Ok(90), &state.settings()));
+
+        let resp = request().method("GET").path("/history").reply(&routes(state)).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body[0]["queue_length"], 90);
+        assert_eq!(body[0]["healthy"], false);
+        assert_eq!(body[0]["reason"], "queue_full");
+    }
+
+    #[tokio::test]
+    async fn history_endpoint_is_not_found_when_disabled() {
+        let state = Arc::new(AppState::new(Settings::default()));
+
+        let resp = request().method("GET").path("/history").reply(&routes(state)).await;
+
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn health_checks_past_the_deadline_are_recorded() {
+        let cache = StatusCache::new(|| async {
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            Ok("Requests in top-level queue : 0".to_string())
+        });
+        let settings = Settings { check_deadline_ms: 20, ..diagnostics() };
+        let state = Arc::new(AppState::with_status_cache(settings, cache));
+
+        request().method("GET").path("/health").reply(&routes(state.clone())).await;
+
+        assert_eq!(state.history.decisions()[0].reason, Reason::DeadlineExceeded);
+    }
+
+    #[tokio::test]
+    async fn status_reports_queue_without_raw_output_by_default() {
+        let state = sample_state(diagnostics());
+
+        let resp = request().method("GET").path("/status").reply(&routes(state)).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["queue_length"], 12);
+        assert_eq!(body["max_queue_length"], 100);
+        assert_eq!(body["effective_threshold_ratio"], 0.8);
+        assert_eq!(body["stale_penalty"], 0.0);
+        assert_eq!(body["processes"], 6);
+        assert_eq!(body["max_pool_size"], 6);
+        assert_eq!(body["healthy"], true);
+        assert!(body.get("raw").is_none());
+        assert!(body.get("memory_warnings").is_none());
+    }
+
+    #[tokio::test]
+    async fn reports_the_passenger_version_once_read() {
+        let state = sample_state(diagnostics());
+        let body: serde_json::Value = serde_json::from_slice(request().path("/status").reply(&routes(state.clone())).await.body()).unwrap();
+        assert!(body.get("passenger_version").is_none());
+
+        state.passenger_version.set("6.0.18".to_string()).unwrap();
+        let body: serde_json::Value = serde_json::from_slice(request().path("/status").reply(&routes(state.clone())).await.body()).unwrap();
+        assert_eq!(body["passenger_version"], "6.0.18");
+        let metrics = request().path("/metrics").reply(&routes(state)).await;
+        assert!(std::str::from_utf8(metrics.body()).unwrap().contains("passenger_version_info{version=\"6.0.18\"} 1\n"));
+    }
+
+    #[tokio::test]
+    async fn pretty_prints_json_when_asked() {
+        let compact = request().path("/status").reply(&routes(sample_state(diagnostics()))).await;
+        assert!(!std::str::from_utf8(compact.body()).unwrap().contains('\n'));
+
+        let pretty = request().path("/status?pretty=true").reply(&routes(sample_state(diagnostics()))).await;
+        assert_eq!(pretty.headers()["content-type"], "application/json");
+        let body = std::str::from_utf8(pretty.body()).unwrap();
+        assert!(body.starts_with("{\n  \""));
+        assert_eq!(serde_json::from_str::<serde_json::Value>(body).unwrap(), serde_json::from_slice::<serde_json::Value>(compact.body()).unwrap());
+
+        let state = sample_state(Settings { pretty_json: true, ..diagnostics() });
+        assert!(std::str::from_utf8(request().path("/info").reply(&routes(state.clone())).await.body()).unwrap().contains('\n'));
+        assert!(!std::str::from_utf8(request().path("/info?pretty=false").reply(&routes(state)).await.body()).unwrap().contains('\n'));
+    }
+
+    #[tokio::test]
+    async fn status_breaks_down_the_score() {
+        let mut settings = diagnostics();
+        settings.scoring.queue_weight = 1.0;
+        settings.scoring.idle_weight = 1.0;
+
+        let resp = request().path("/status").reply(&routes(sample_state(settings))).await;
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["score"]["score"], 44.0);
+        assert_eq!(body["score"]["queue"], 88.0);
+        assert_eq!(body["score"]["idle"], 0.0);
+        assert!(body["score"].get("memory").is_none());
+        assert_eq!(body["reason"], "low_score");
+    }
+
+    #[tokio::test]
+    async fn status_says_when_the_host_is_degraded() {
+        let state = sample_state(Settings { degraded_queue_ratio: Some(0.1), ..diagnostics() });
+        let body: serde_json::Value = serde_json::from_slice(request().path("/status").reply(&routes(state)).await.body()).unwrap();
+        assert_eq!((body["healthy"].clone(), body["degraded"].clone()), (true.into(), true.into()));
+
+        let body: serde_json::Value = serde_json::from_slice(request().path("/status").reply(&routes(sample_state(diagnostics()))).await.body()).unwrap();
+        assert!(body.get("degraded").is_none());
+    }
+
+    /// Two instances, `a` with room in its queue and `b` full.
+    fn two_instances() -> Arc<AppState> {
+        let instance = |queue_length: i32| {
+            let output = format!("Requests in top-level queue : {}", queue_length);
+            StatusCache::new(move || {
+                let output = output.clone();
+                async move { Ok(output) }
+            })
+        };
+        let instances = vec![("passenger-status --instance a".to_string(), instance(3)), ("passenger-status --instance b".to_string(), instance(90))];
+        Arc::new(AppState::with_instances(diagnostics(), instances))
+    }
+
+    #[tokio::test]
+    async fn status_lists_each_instance_when_there_are_several() {
+        let state = two_instances();
+
+        let resp = request().path("/status").reply(&routes(state.clone())).await;
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(body["queue_length"], 90);
+        assert_eq!(body["healthy"], false);
+        assert_eq!(body["instances"][0]["command"], "passenger-status --instance a");
+        assert_eq!(body["instances"][0]["queue_length"], 3);
+        assert_eq!(body["instances"][1]["reason"], "queue_full");
+
+        let resp = request().path("/health").reply(&routes(state)).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn info_and_dashboard_agree_with_health_across_instances() {
+        let state = two_instances();
+
+        assert_eq!(request().path("/health").reply(&routes(state.clone())).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let res = request().path("/info").reply(&routes(state.clone())).await;
+        let info: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!((&info["healthy"], &info["queue_length"]), (&serde_json::json!(false), &serde_json::json!(90)));
+        let dashboard = request().path("/dashboard").reply(&routes(state)).await;
+        let html = std::str::from_utf8(dashboard.body()).unwrap();
+        assert!(html.contains("unhealthy") && html.contains("<td>Queue length</td><td>90</td>"), "{}", html);
+    }
+
+    #[tokio::test]
+    async fn status_agrees_with_health_when_the_pool_is_full() {
+        // All six processes running with 12 queued
+        let state = sample_state(Settings { full_pool_queue_threshold: Some(5), capacity_report: true, ..diagnostics() });
+
+        let health = request().path("/health").reply(&routes(state.clone())).await;
+        assert_eq!(health.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(health.headers()["x-health-reason"], "pool_exhausted");
+        let body: serde_json::Value = serde_json::from_slice(request().path("/status").reply(&routes(state)).await.body()).unwrap();
+        assert_eq!((&body["healthy"], &body["reason"]), (&serde_json::json!(false), &serde_json::json!("pool_exhausted")));
+        assert!(body.get("capacity").is_some());
+    }
+
+    #[tokio::test]
+    async fn status_warns_about_processes_over_the_memory_limit() {
+        let state = sample_state(Settings { max_process_memory_mb: Some(405), ..diagnostics() });
+
+        let resp = request().method("GET").path("/status").reply(&routes(state)).await;
+
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert_eq!(
+            body["memory_warnings"],
+            serde_json::json!([{ "pid": 2231, "memory_mb": 410 }, { "pid": 2263, "memory_mb": 415 }])
+        );
+        assert_eq!(body["healthy"], true);
+    }
+
+    #[tokio::test]
+    async fn status_includes_raw_output_when_allowed() {
+        let state = sample_state(Settings { allow_raw_output: true, ..diagnostics() });
+
+        let resp = request().method("GET").path("/status?include_raw=true").reply(&routes(state)).await;
+
+        let body: serde_json::Value = serde_json::from_slice(resp.body()).unwrap();
+        assert!(body["raw"].as_str().unwrap().contains("Phusion_Passenger/5.3.7"));
+    }
+
+    fn admin_from(cidrs: &str) -> Settings {
+        Settings { admin_allow_cidrs: cidrs.parse().unwrap(), trusted_proxies: "10.0.0.0/8".parse().unwrap(), ..diagnostics() }
+    }
+
+    #[tokio::test]
+    async fn diagnostics_are_limited_to_allowed_clients() {
+        let routes = routes(sample_state(admin_from("192.168.0.0/16")));
+        let from = |ip: &str| request().remote_addr(format!("{}:40000", ip).parse().unwrap());
+
+        assert_eq!(from("192.168.1.5").path("/status").reply(&routes).await.status(), StatusCode::OK);
+        assert_eq!(from("203.0.113.9").path("/status").reply(&routes).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(from("203.0.113.9").path("/metrics").reply(&routes).await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(from("203.0.113.9").path("/health").reply(&routes).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn diagnostics_allowlist_checks_the_client_behind_a_trusted_proxy() {
+        let routes = routes(sample_state(admin_from("192.168.0.0/16")));
+        let via_proxy = |forwarded_for: &str| {
+            request().remote_addr("10.0.0.1:40000".parse().unwrap()).header("x-forwarded-for", forwarded_for).path("/status")
+        };
+
+        assert_eq!(via_proxy("192.168.1.5").reply(&routes).await.status(), StatusCode::OK);
+        assert_eq!(via_proxy("192.168.1.5, 203.0.113.9").reply(&routes).await.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn token_buckets_refill_at_the_rate() {
+        let bucket = TokenBucket::new();
+        let at = Duration::from_millis;
+
+        assert!(bucket.take(2, at(0)));
+        assert!(bucket.take(2, at(0)));
+        assert!(!bucket.take(2, at(0)));
+        assert!(!bucket.take(2, at(400)));
+        assert!(bucket.take(2, at(500)));
+        assert!(!bucket.take(2, at(500)));
+        assert!(bucket.take(2, at(5000)));
+        assert!(bucket.take(2, at(5000)));
+        assert!(!bucket.take(2, at(5000)));
+    }
+
+    #[tokio::test]
+    async fn diagnostics_answer_429_past_the_rate_limit_but_probes_dont() {
+        let routes = routes(sample_state(Settings { metrics_rate_limit_per_sec: Some(1), ..diagnostics() }));
+
+        assert_eq!(request().path("/status").reply(&routes).await.status(), StatusCode::OK);
+        let resp = request().path("/status").reply(&routes).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers()["retry-after"], "1");
+        // Each endpoint has a limit of its own
+        assert_eq!(request().path("/metrics").reply(&routes).await.status(), StatusCode::OK);
+        for _ in 0..3 {
+            assert_eq!(request().path("/health").reply(&routes).await.status(), StatusCode::OK);
+            assert_eq!(request().path("/readyz").reply(&routes).await.status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn status_refuses_raw_output_unless_allowed() {
+        let state = sample_state(diagnostics());
+
+        let resp = request().method("GET").path("/status?include_raw=true").reply(&routes(state)).await;
+
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn fresh_status_reads_skip_the_cache_for_token_holders() {
+        let reads = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counted = reads.clone();
+        let cache = StatusCache::new(move || {
+            let read = counted.fetch_add(1, Ordering::SeqCst) + 1;
+            async move { Ok(format!("Requests in top-level queue : {}", read)) }
+        });
+        let settings = Settings { stale_after_ms: 60_000, ..token_holder() };
+        let routes = routes(Arc::new(AppState::with_status_cache(settings, cache)));
+        let queue_length = |resp: warp::http::Response<warp::hyper::body::Bytes>| {
+            serde_json::from_slice::<serde_json::Value>(resp.body()).unwrap()["queue_length"].clone()
+        };
+
+        assert_eq!(queue_length(request().path("/status").reply(&routes).await), 1);
+        assert_eq!(queue_length(request().path("/status").reply(&routes).await), 1);
+        let fresh = request().path("/status?fresh=true").header("authorization", "Bearer s3cret").reply(&routes).await;
+        assert_eq!(queue_length(fresh), 2);
+        assert_eq!(queue_length(request().path("/status").reply(&routes).await), 2);
+        assert_eq!(request().path("/status?fresh=true").reply(&routes).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(reads.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn info_combines_health_capacity_and_instance() {
+        let settings = Settings { instance_label: Some("web-1".to_string()), ..diagnostics() };
+        let state = sample_state(settings);
+
+        let res = request().path("/info").reply(&routes(state)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let info: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(
+            info,
+            serde_json::json!({
+                "healthy": true,
+                "queue_length": 12,
+                "capacity_percent": 12.0,
+                "instance": "web-1",
+                "version": env!("CARGO_PKG_VERSION"),
+            })
+        );
+    }
+
+    fn token_holder() -> Settings {
+        Settings { admin_token: Some("s3cret".to_string()), ..diagnostics() }
+    }
+
+    fn put_threshold(ratio: f64, authorization: &str) -> warp::test::RequestBuilder {
+        request()
+            .method("PUT")
+            .path("/threshold")
+            .header("authorization", authorization)
+            .json(&serde_json::json!({ "queue_threshold_ratio": ratio }))
+    }
+
+    #[tokio::test]
+    async fn threshold_can_be_changed_with_the_admin_token() {
+        let state = sample_state(token_holder());
+        let routes = routes(state.clone());
+
+        let res = request().path("/threshold").reply(&routes).await;
+        assert_eq!(res.body(), r#"{"queue_threshold_ratio":0.8}"#);
+
+        let res = put_threshold(0.1, "Bearer s3cret").reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(state.settings().queue_threshold_ratio, 0.1);
+        assert_eq!(state.overrides(&[]), vec![("queue_threshold_ratio".to_string(), "0.1".to_string())]);
+
+        let res = request().path("/health").reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn threshold_changes_need_a_valid_token_and_ratio() {
+        let with_token = routes(sample_state(token_holder()));
+        assert_eq!(put_threshold(0.5, "Bearer guess").reply(&with_token).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(put_threshold(1.5, "Bearer s3cret").reply(&with_token).await.status(), StatusCode::BAD_REQUEST);
+        let not_json = request().method("PUT").path("/threshold").header("authorization", "Bearer s3cret").body("lots");
+        assert_eq!(not_json.reply(&with_token).await.status(), StatusCode::BAD_REQUEST);
+
+        let without_token = routes(sample_state(diagnostics()));
+        assert_eq!(put_threshold(0.5, "Bearer ").reply(&without_token).await.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn threshold_changes_are_saved_to_the_persist_file() {
+        let path = std::env::temp_dir().join(format!("passenger-ready-threshold-{}", std::process::id()));
+        let settings = Settings { persist_threshold_file: Some(path.to_string_lossy().into_owned()), ..token_holder() };
+
+        put_threshold(0.6, "Bearer s3cret").reply(&routes(sample_state(settings))).await;
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "0.6\n");
+        assert_eq!(persisted_threshold(path.to_str().unwrap()), Some(0.6));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn writes_metrics_to_the_textfile() {
+        let path = std::env::temp_dir().join(format!("passenger-ready-{}.prom", std::process::id()));
+        let settings = Settings { metrics_textfile_path: Some(path.to_string_lossy().into_owned()), ..Settings::default() };
+        let writer = tokio::spawn(write_metrics_textfile(sample_state(settings)));
+
+        let written = tokio::time::timeout(Duration::from_secs(5), async {
+            while !path.exists() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        assert!(written.await.is_ok());
+        writer.abort();
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.contains("# TYPE passenger_queue_length gauge\npassenger_queue_length 12\n"), "{}", text);
+        assert!(!path.with_extension("prom.tmp").exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn config_sources_tells_overrides_from_defaults() {
+        let state = sample_state(token_holder());
+        *state.cli_overrides.lock().unwrap() = vec![("max_queue_length".to_string(), "50".to_string())];
+        let routes = routes(state);
+        put_threshold(0.4, "Bearer s3cret").reply(&routes).await;
+
+        let res = request().path("/config/sources").reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let sources: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(sources["max_queue_length"], "override");
+        assert_eq!(sources["queue_threshold_ratio"], "runtime");
+        assert_eq!(sources["endpoints.health"], "default");
+    }
+
+    #[tokio::test]
+    async fn errors_lists_recent_failed_reads_to_token_holders() {
+        let settings = Settings { status_retries: 1, error_buffer_size: 3, ..token_holder() };
+        let cache = StatusCache::new(|| async { Err(QueueError::Timeout.into()) });
+        let state = Arc::new(AppState::with_status_cache(settings, cache));
+        let failing = routes(state);
+        request().path("/health").reply(&failing).await;
+        request().path("/health").reply(&failing).await;
+
+        assert_eq!(request().path("/errors").reply(&failing).await.status(), StatusCode::UNAUTHORIZED);
+        let res = request().path("/errors").header("authorization", "Bearer s3cret").reply(&failing).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let errors: Vec<serde_json::Value> = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(errors.len(), 3);
+        assert_eq!(errors[0]["kind"], "timeout");
+        assert_eq!(errors[0]["message"], "passenger-status timed out");
+        assert!(errors[0]["timestamp"].as_u64().unwrap() > 0);
+
+        let without_token = routes(sample_state(diagnostics()));
+        assert_eq!(request().path("/errors").reply(&without_token).await.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn metrics_are_served_as_prometheus_text() {
+        let res = request().path("/metrics").reply(&routes(sample_state(diagnostics()))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "text/plain; version=0.0.4");
+        let body = std::str::from_utf8(res.body()).unwrap();
+        assert!(body.contains("passenger_queue_length 12\n"));
+        assert!(body.contains("passenger_queue_stale 0\n"));
+    }
+}
@@ -0,0 +1,88 @@
+//! Error responses. With `json_errors` every endpoint fails with the same
+//! `{"error": {"code": "...", "message": "..."}}` document, the `code` one of `ErrorCode`'s;
+//! otherwise the message is sent as plain text, or warp's own response is kept for rejections.
+
+use crate::settings::Settings;
+use serde::Serialize;
+use warp::http::StatusCode;
+use warp::reject::{InvalidHeader, InvalidQuery, LengthRequired, MethodNotAllowed, MissingHeader, PayloadTooLarge, UnsupportedMediaType};
+use warp::{Rejection, Reply};
+
+/// Why a request failed, as the `code` of a JSON error. The names are stable, so tooling can branch
+/// on them.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// No endpoint serves the path, or `/health/:app` doesn't know the app.
+    NotFound,
+    MethodNotAllowed,
+    /// A malformed query string, header or body.
+    BadRequest,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    /// The client isn't in `admin_allow_cidrs`.
+    Forbidden,
+    /// A missing or wrong `admin_token`.
+    Unauthorized,
+    /// The endpoint needs `admin_token`, which isn't set.
+    TokenNotConfigured,
+    /// `?include_raw=true` without `allow_raw_output`.
+    RawOutputDisabled,
+    /// A `PUT /threshold` ratio outside 0–1.
+    InvalidThreshold,
+    /// `PUT /threshold` couldn't write `persist_threshold_file`.
+    PersistFailed,
+    /// The endpoint has had its `metrics_rate_limit_per_sec` for now.
+    RateLimited,
+    Internal,
+}
+
+#[derive(Debug, Serialize)]
+struct Envelope<'a> {
+    error: ErrorBody<'a>,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody<'a> {
+    code: ErrorCode,
+    message: &'a str,
+}
+
+/// A `status` response for `code`, as `json_errors` says.
+pub(crate) fn error_reply(settings: &Settings, status: StatusCode, code: ErrorCode, message: &str) -> warp::reply::Response {
+    if !settings.json_errors {
+        return warp::reply::with_status(message.to_string(), status).into_response();
+    }
+    let envelope = Envelope { error: ErrorBody { code, message } };
+    warp::reply::with_status(warp::reply::json(&envelope), status).into_response()
+}
+
+/// With `json_errors`, answers a rejection warp would otherwise answer itself, like an unknown path
+/// or method, with its code. Rejections it doesn't know of are `internal`, without the details.
+pub(crate) fn rejection_reply(settings: &Settings, rejection: &Rejection) -> Option<warp::reply::Response> {
+    if !settings.json_errors {
+        return None;
+    }
+    let (status, code, message) = if rejection.is_not_found() {
+        (StatusCode::NOT_FOUND, ErrorCode::NotFound, "not found".to_string())
+    } else if let Some(e) = rejection.find::<MethodNotAllowed>() {
+        (StatusCode::METHOD_NOT_ALLOWED, ErrorCode::MethodNotAllowed, e.to_string())
+    } else if let Some(e) = rejection.find::<InvalidQuery>() {
+        (StatusCode::BAD_REQUEST, ErrorCode::BadRequest, e.to_string())
+    } else if let Some(e) = rejection.find::<InvalidHeader>() {
+        (StatusCode::BAD_REQUEST, ErrorCode::BadRequest, e.to_string())
+    } else if let Some(e) = rejection.find::<MissingHeader>() {
+        (StatusCode::BAD_REQUEST, ErrorCode::BadRequest, e.to_string())
+    } else if let Some(e) = rejection.find::<warp::body::BodyDeserializeError>() {
+        (StatusCode::BAD_REQUEST, ErrorCode::BadRequest, e.to_string())
+    } else if let Some(e) = rejection.find::<LengthRequired>() {
+        (StatusCode::LENGTH_REQUIRED, ErrorCode::BadRequest, e.to_string())
+    } else if let Some(e) = rejection.find::<PayloadTooLarge>() {
+        (StatusCode::PAYLOAD_TOO_LARGE, ErrorCode::PayloadTooLarge, e.to_string())
+    } else if let Some(e) = rejection.find::<UnsupportedMediaType>() {
+        (StatusCode::UNSUPPORTED_MEDIA_TYPE, ErrorCode::UnsupportedMediaType, e.to_string())
+    } else {
+        (StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Internal, "internal error".to_string())
+    };
+    Some(error_reply(settings, status, code, &message))
+}
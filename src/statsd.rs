@@ -0,0 +1,82 @@
+use crate::check::Decision;
+use crate::settings::Settings;
+use log::debug;
+use tokio::net::UdpSocket;
+
+const METRIC_PREFIX: &str = "passenger_ready";
+
+/// Sends a decision's metrics to `statsd_host`:`statsd_port`, if a host is set. Sending happens in the
+/// background and failures are only logged at debug level; StatsD is fire-and-forget anyway.
+pub fn emit(settings: &Settings, decision: &Decision) {
+    let host = match settings.statsd_host.as_deref() {
+        Some(host) if !host.is_empty() => host.to_string(),
+        _ => return,
+    };
+    let payload = format_metrics(decision, settings.instance_label.as_deref());
+    let port = settings.statsd_port;
+    tokio::spawn(async move {
+        if let Err(e) = send(&payload, (host.as_str(), port)).await {
+            debug!("Failed to send StatsD metrics to {}:{}: {}", host, port, e);
+        }
+    });
+}
+
+/// A decision as newline-separated StatsD lines: the queue length as a gauge (when it was measured)
+/// and a counter for the check's result. `instance` becomes a DogStatsD tag.
+pub fn format_metrics(decision: &Decision, instance: Option<&str>) -> String {
+    let tags = match instance {
+        Some(instance) => format!("|#instance:{}", instance),
+        None => String::new(),
+    };
+    let result = if decision.healthy { "healthy" } else { "unhealthy" };
+
+    let mut lines = Vec::new();
+    if let Some(queue_length) = decision.queue_length {
+        lines.push(format!("{}.queue_length:{}|g{}", METRIC_PREFIX, queue_length, tags));
+    }
+    lines.push(format!("{}.check.{}:1|c{}", METRIC_PREFIX, result, tags));
+    lines.join("\n")
+}
+
+async fn send(payload: &str, addr: (&str, u16)) -> std::io::Result<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", 0)).await?;
+    socket.send_to(payload.as_bytes(), addr).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::check::Reason;
+
+    #[test]
+    fn formats_queue_gauge_and_result_counter() {
+        let decision = Decision::new(Some(12), true, Reason::Ok);
+        assert_eq!(
+            format_metrics(&decision, None),
+            "passenger_ready.queue_length:12|g\npassenger_ready.check.healthy:1|c"
+        );
+    }
+
+    #[test]
+    fn tags_metrics_with_the_instance_label() {
+        let decision = Decision::new(None, false, Reason::PassengerDown);
+        assert_eq!(
+            format_metrics(&decision, Some("web-1")),
+            "passenger_ready.check.unhealthy:1|c|#instance:web-1"
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_metrics_over_udp() {
+        let receiver = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let port = receiver.local_addr().unwrap().port();
+        let settings = Settings { statsd_host: Some("127.0.0.1".to_string()), statsd_port: port, ..Settings::default() };
+
+        emit(&settings, &Decision::new(Some(3), true, Reason::Ok));
+
+        let mut buf = [0; 512];
+        let len = receiver.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"passenger_ready.queue_length:3|g\npassenger_ready.check.healthy:1|c");
+    }
+}
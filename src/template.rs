@@ -0,0 +1,54 @@
+//! `health_body_template`: a `/health` body with placeholders like `{queue}` filled in for each
+//! response, for load balancers that match on a particular string. `{{` and `}}` are literal braces.
+
+/// The placeholders a template can use.
+pub const PLACEHOLDERS: [&str; 6] = ["queue", "max", "state", "headroom", "reason", "healthy"];
+
+/// Checks that every placeholder is one of `PLACEHOLDERS` and every brace is closed or escaped, so a
+/// bad template fails at load rather than on every response.
+pub fn validate(template: &str) -> Result<(), String> {
+    render(template, |_| String::new()).map(|_| ())
+}
+
+/// `template` with each placeholder replaced by `value(name)`.
+pub fn render(template: &str, value: impl Fn(&str) -> String) -> Result<String, String> {
+    let mut body = String::with_capacity(template.len());
+    let mut chars = template.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match c {
+            '{' if chars.next_if(|&(_, c)| c == '{').is_some() => body.push('{'),
+            '}' if chars.next_if(|&(_, c)| c == '}').is_some() => body.push('}'),
+            '{' => {
+                let end = template[start..].find('}').map(|end| start + end).ok_or_else(|| format!("unclosed '{{' at {}", start))?;
+                let name = &template[start + 1..end];
+                if !PLACEHOLDERS.contains(&name) {
+                    return Err(format!("unknown placeholder {{{}}}, expected one of {}", name, PLACEHOLDERS.join(", ")));
+                }
+                body.push_str(&value(name));
+                while chars.next_if(|&(i, _)| i <= end).is_some() {}
+            }
+            '}' => return Err(format!("unmatched '}}' at {}; write '}}}}' for a literal brace", start)),
+            c => body.push(c),
+        }
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fills_in_placeholders_and_unescapes_braces() {
+        let body = render("{{\"up\": {healthy}, \"queue\": {queue}}} of {max}", |name| name.to_uppercase()).unwrap();
+        assert_eq!(body, "{\"up\": HEALTHY, \"queue\": QUEUE} of MAX");
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders_and_stray_braces() {
+        assert!(validate("queue={queue} state={state}").is_ok());
+        assert!(validate("{queue_length}").unwrap_err().contains("unknown placeholder {queue_length}"));
+        assert!(validate("queue={queue").unwrap_err().contains("unclosed"));
+        assert!(validate("queue}").unwrap_err().contains("unmatched"));
+    }
+}
@@ -0,0 +1,302 @@
+//! An in-process server for end-to-end tests. Unlike `warp::test`, requests go over a real socket
+//! through the same route assembly and connection handling as `server::run`.
+
+use crate::passenger::{StatusCache, StatusProvider};
+use crate::listener::Listener;
+use crate::server::{serve, AppState};
+use crate::settings::Settings;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UnixStream};
+use tokio::sync::oneshot;
+
+/// A running server. It shuts down when dropped.
+pub struct TestServer {
+    pub base_url: String,
+    _shutdown: oneshot::Sender<()>,
+}
+
+/// Serves every route on an ephemeral port, and on `unix_socket_path` when that's set, reading the
+/// queue from `provider` instead of passenger-status.
+pub async fn spawn_server(settings: Settings, provider: impl StatusProvider + 'static) -> TestServer {
+    let state = Arc::new(AppState::with_status_cache(settings, StatusCache::new(provider)));
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let mut listeners = vec![Listener::from(listener)];
+    if let Some(path) = state.settings().unix_socket_path {
+        listeners.push(Listener::bind_unix(&path).unwrap());
+    }
+    let (shutdown, stopped) = oneshot::channel::<()>();
+    tokio::spawn(serve(state, listeners, async {
+        stopped.await.ok();
+    }));
+    TestServer { base_url, _shutdown: shutdown }
+}
+
+/// State whose passenger-status is the busy Passenger 5 sample.
+pub fn sample_state(settings: Settings) -> Arc<AppState> {
+    sample_state_with(settings, include_str!("../samples/passenger-5-busy.txt"))
+}
+
+/// State whose passenger-status is always `output`.
+pub fn sample_state_with(settings: Settings, output: &'static str) -> Arc<AppState> {
+    let cache = StatusCache::new(move || async move { Ok(output.to_string()) });
+    Arc::new(AppState::with_status_cache(settings, cache))
+}
+
+/// A response as read off the wire.
+pub struct Response {
+    pub status: u16,
+    pub reason: String,
+    headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+}
+
+/// Sends `GET url` with the given extra headers.
+pub async fn get(url: &str, headers: &[(&str, &str)]) -> Response {
+    send("GET", url, headers).await
+}
+
+/// Sends a `method` request with the given extra headers. HTTP/1.0, so the body runs to the end of
+/// the connection.
+pub async fn send(method: &str, url: &str, headers: &[(&str, &str)]) -> Response {
+    let address = url.strip_prefix("http://").unwrap();
+    let (host, path) = match address.find('/') {
+        Some(start) => address.split_at(start),
+        None => (address, "/"),
+    };
+    exchange(TcpStream::connect(host).await.unwrap(), method, host, path, headers).await
+}
+
+/// Sends `GET path` over the Unix socket at `socket`.
+pub async fn get_unix(socket: &str, path: &str) -> Response {
+    exchange(UnixStream::connect(socket).await.unwrap(), "GET", "localhost", path, &[]).await
+}
+
+async fn exchange(mut stream: impl AsyncRead + AsyncWrite + Unpin, method: &str, host: &str, path: &str, headers: &[(&str, &str)]) -> Response {
+    let mut request = format!("{} {} HTTP/1.0\r\nHost: {}\r\n", method, path, host);
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut raw = String::new();
+    stream.read_to_string(&mut raw).await.unwrap();
+    let (head, body) = raw.split_once("\r\n\r\n").unwrap();
+    let mut lines = head.lines();
+    let mut status_line = lines.next().unwrap().splitn(3, ' ').skip(1);
+    let status = status_line.next().unwrap().parse().unwrap();
+    let reason = status_line.next().unwrap_or_default().to_string();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.to_string(), value.trim().to_string()))
+        .collect();
+    Response { status, reason, headers, body: body.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::Endpoints;
+
+    fn busy() -> impl StatusProvider {
+        || async { Ok(include_str!("../samples/passenger-5-busy.txt").to_string()) }
+    }
+
+    fn multi_app() -> impl StatusProvider {
+        || async { Ok(include_str!("../samples/passenger-6-multi-app.txt").to_string()) }
+    }
+
+    #[tokio::test]
+    async fn serves_health_over_http() {
+        let server = spawn_server(Settings::default(), busy()).await;
+        let res = get(&format!("{}/health", server.base_url), &[]).await;
+
+        assert_eq!(res.status, 200);
+        assert_eq!(res.header("x-health-reason"), Some("ok"));
+        assert_eq!(res.body, "true");
+    }
+
+    #[tokio::test]
+    async fn serves_the_same_routes_over_tcp_and_a_unix_socket() {
+        let path = std::env::temp_dir().join(format!("passenger-ready-{}-both.sock", std::process::id())).to_string_lossy().into_owned();
+        let settings = Settings { unix_socket_path: Some(path.clone()), ..Settings::default() };
+        let server = spawn_server(settings, busy()).await;
+
+        let over_tcp = get(&format!("{}/health", server.base_url), &[]).await;
+        let over_unix = get_unix(&path, "/health").await;
+        assert_eq!((over_tcp.status, over_tcp.body.as_str()), (200, "true"));
+        assert_eq!((over_unix.status, over_unix.body.as_str()), (200, "true"));
+        assert_eq!(get_unix(&path, "/nowhere").await.status, 404);
+
+        // Shutting down stops both and removes the socket file
+        let address = server.base_url.trim_start_matches("http://").to_string();
+        drop(server);
+        for _ in 0..50 {
+            if !std::path::Path::new(&path).exists() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(!std::path::Path::new(&path).exists());
+        assert!(TcpStream::connect(address).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn echoes_the_listed_request_headers() {
+        let settings = Settings { echo_headers: vec!["X-Request-Id".to_string(), "X-Trace".to_string()], ..Settings::default() };
+        let server = spawn_server(settings, busy()).await;
+        let res = get(&format!("{}/health", server.base_url), &[("X-Request-Id", "abc123"), ("X-Other", "1")]).await;
+
+        assert_eq!(res.header("x-request-id"), Some("abc123"));
+        assert_eq!(res.header("x-trace"), None);
+        assert_eq!(res.header("x-other"), None);
+    }
+
+    #[tokio::test]
+    async fn puts_the_reason_in_the_status_line_when_asked() {
+        let server = spawn_server(Settings { max_queue_length: 5, ..Settings::default() }, busy()).await;
+        let res = get(&format!("{}/health", server.base_url), &[]).await;
+        assert_eq!((res.status, res.reason.as_str()), (503, "Service Unavailable"));
+        assert_eq!(res.header("x-status-reason"), None);
+
+        let server = spawn_server(Settings { max_queue_length: 5, reason_phrase: true, ..Settings::default() }, busy()).await;
+        let res = get(&format!("{}/health", server.base_url), &[]).await;
+        assert_eq!((res.status, res.reason.as_str()), (503, "QUEUE_FULL"));
+        assert_eq!(res.header("x-status-reason"), Some("QUEUE_FULL"));
+    }
+
+    #[tokio::test]
+    async fn serves_only_the_enabled_endpoints() {
+        let server = spawn_server(Settings::default(), multi_app()).await;
+        for path in ["/health", "/health/api", "/readyz"] {
+            let res = get(&format!("{}{}", server.base_url, path), &[]).await;
+            assert_ne!(res.status, 404, "{} was not routed", path);
+        }
+        for path in ["/status", "/info", "/metrics", "/history", "/dashboard", "/threshold", "/errors", "/config/sources"] {
+            assert_eq!(get(&format!("{}{}", server.base_url, path), &[]).await.status, 404, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn paths_may_end_in_a_slash() {
+        let settings = Settings { endpoints: Endpoints::all(), ..Settings::default() };
+        let server = spawn_server(settings, multi_app()).await;
+        let mut paths = vec!["/health", "/health/api", "/readyz"];
+        if cfg!(feature = "full") {
+            paths.extend(["/status", "/info", "/metrics", "/dashboard", "/history", "/threshold", "/errors", "/config/sources"]);
+        }
+        for path in paths {
+            let without = get(&format!("{}{}", server.base_url, path), &[]).await;
+            let with = get(&format!("{}{}/", server.base_url, path), &[]).await;
+
+            assert_ne!(without.status, 404, "{}", path);
+            assert_eq!(with.status, without.status, "{}/", path);
+            assert_eq!(with.header("content-type"), without.header("content-type"), "{}/", path);
+        }
+        for path in ["/readyz/extra", "/status/extra", "/health//"] {
+            assert_eq!(get(&format!("{}{}", server.base_url, path), &[]).await.status, 404, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn head_requests_get_headers_without_a_body() {
+        let settings = Settings { endpoints: Endpoints::all(), ..Settings::default() };
+        let server = spawn_server(settings, multi_app()).await;
+        for path in ["/health", "/health/api", "/readyz", "/status", "/info", "/metrics", "/dashboard", "/history", "/threshold", "/errors", "/config/sources"] {
+            let url = format!("{}{}", server.base_url, path);
+            let (head, get) = (send("HEAD", &url, &[]).await, get(&url, &[]).await);
+
+            assert_eq!(head.status, get.status, "{}", path);
+            assert_eq!(head.header("content-type"), get.header("content-type"), "{}", path);
+            assert_eq!(head.body, "", "{}", path);
+        }
+    }
+
+    #[cfg(feature = "full")]
+    #[tokio::test]
+    async fn checks_the_allowlist_against_the_connection() {
+        let allowing = |cidrs: &str| Settings { endpoints: Endpoints::all(), admin_allow_cidrs: cidrs.parse().unwrap(), ..Settings::default() };
+
+        let server = spawn_server(allowing("127.0.0.0/8"), busy()).await;
+        assert_eq!(get(&format!("{}/status", server.base_url), &[]).await.status, 200);
+        let server = spawn_server(allowing("10.0.0.0/8"), busy()).await;
+        assert_eq!(get(&format!("{}/status", server.base_url), &[]).await.status, 403);
+    }
+
+    #[cfg(feature = "full")]
+    #[tokio::test]
+    async fn measures_how_long_each_endpoint_takes() {
+        let server = spawn_server(Settings { endpoints: Endpoints::all(), ..Settings::default() }, busy()).await;
+        get(&format!("{}/health", server.base_url), &[]).await;
+        get(&format!("{}/nowhere", server.base_url), &[]).await;
+        let metrics = get(&format!("{}/metrics", server.base_url), &[]).await.body;
+
+        assert!(metrics.contains("passenger_ready_request_duration_seconds{endpoint=\"health\",quantile=\"0.95\"} "), "{}", metrics);
+        assert!(!metrics.contains("nowhere"));
+    }
+
+    #[cfg(feature = "full")]
+    #[tokio::test]
+    async fn every_error_has_the_same_shape_with_json_errors() {
+        let settings = Settings { endpoints: Endpoints::all(), json_errors: true, admin_allow_cidrs: "127.0.0.0/8".parse().unwrap(), ..Settings::default() };
+        let server = spawn_server(settings, busy()).await;
+        let error = |res: Response| {
+            let body: serde_json::Value = serde_json::from_str(&res.body).unwrap();
+            (res.status, body["error"]["code"].as_str().unwrap().to_string(), body["error"]["message"].is_string())
+        };
+
+        let url = |path: &str| format!("{}{}", server.base_url, path);
+        assert_eq!(error(get(&url("/nowhere"), &[]).await), (404, "not_found".to_string(), true));
+        assert_eq!(error(get(&url("/health/nope"), &[]).await), (404, "not_found".to_string(), true));
+        assert_eq!(error(send("DELETE", &url("/threshold"), &[]).await), (405, "method_not_allowed".to_string(), true));
+        assert_eq!(error(get(&url("/errors"), &[]).await), (403, "token_not_configured".to_string(), true));
+        assert_eq!(error(get(&url("/status?include_raw=true"), &[]).await), (403, "raw_output_disabled".to_string(), true));
+        assert_eq!(error(get(&url("/status?include_raw=maybe"), &[]).await), (400, "bad_request".to_string(), true));
+
+        let server = spawn_server(Settings { endpoints: Endpoints::all(), json_errors: true, admin_allow_cidrs: "10.0.0.0/8".parse().unwrap(), ..Settings::default() }, busy()).await;
+        assert_eq!(error(get(&format!("{}/status", server.base_url), &[]).await), (403, "forbidden".to_string(), true));
+    }
+
+    #[tokio::test]
+    async fn errors_are_plain_text_by_default() {
+        let server = spawn_server(Settings::default(), busy()).await;
+        let res = get(&format!("{}/nowhere", server.base_url), &[]).await;
+        assert_eq!((res.status, res.body.as_str()), (404, ""));
+    }
+
+    #[tokio::test]
+    async fn caps_passenger_status_runs_per_request() {
+        let runs = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = runs.clone();
+        let provider = move || {
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async { Err(crate::error::QueueError::Timeout.into()) }
+        };
+        let settings = Settings { status_retries: 5, fail_open: true, max_total_subprocess_spawns_per_request: 2, ..Settings::default() };
+        let server = spawn_server(settings, provider).await;
+        let res = get(&format!("{}/health", server.base_url), &[("Accept", "application/json")]).await;
+
+        assert_eq!(res.status, 503);
+        assert_eq!(runs.load(std::sync::atomic::Ordering::SeqCst), 2);
+        let decision: serde_json::Value = serde_json::from_str(&res.body).unwrap();
+        assert_eq!(decision["error"], "passenger-status already ran 2 times for this request");
+    }
+
+    #[tokio::test]
+    async fn reports_an_unhealthy_provider() {
+        let server = spawn_server(Settings::default(), || async { Err(anyhow::anyhow!("boom")) }).await;
+        let res = get(&format!("{}/health", server.base_url), &[("Accept", "application/json")]).await;
+
+        assert_eq!(res.status, 503);
+        let decision: serde_json::Value = serde_json::from_str(&res.body).unwrap();
+        assert_eq!(decision["healthy"], false);
+    }
+}